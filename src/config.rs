@@ -0,0 +1,430 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result::Result;
+use std::borrow::ToOwned;
+use toml;
+use matcher::DeviceMatcher;
+use mapping::AspectMode;
+use mapping::Geometry;
+use outputs::OutputSelector;
+use hotkey::Hotkey;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(io::Error),
+    ParseError(String),
+    LogicError(String),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::IOError(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::IOError(ref e) => write!(fmt, "{}", e),
+            ConfigError::ParseError(ref s) => write!(fmt, "parse error: {}", s),
+            ConfigError::LogicError(ref s) => write!(fmt, "{}", s),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Target {
+    // Outputs in the order of preference
+    Output(Vec<OutputSelector>),
+    Area(Geometry),
+}
+
+impl Target {
+    // Accepts what Display prints (`output OUTPUT[,OUTPUT...]` or `area AREA`), as well as
+    // an area or a list of outputs on its own
+    pub fn parse(s: &str) -> Result<Target, String> {
+        let parse_outputs = |list: &str| OutputSelector::parse_list(&list.split(',').collect::<Vec<_>>()).map(Target::Output);
+        if s.starts_with("output ") {
+            parse_outputs(&s["output ".len()..])
+        } else if s.starts_with("area ") {
+            Geometry::parse(&s["area ".len()..]).map(Target::Area)
+        } else {
+            Geometry::parse(s).map(Target::Area).or_else(|_| parse_outputs(s))
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Target::Output(ref selectors) => {
+                let names: Vec<_> = selectors.iter().map(|s| s.to_string()).collect();
+                write!(fmt, "output {}", names.join(","))
+            }
+            Target::Area(ref geometry) => write!(fmt, "area {}", geometry),
+        }
+    }
+}
+
+// What to do with a device when none of the outputs of its rule is active
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MissingPolicy {
+    // Use the first active output
+    Next,
+    // Map the device to all outputs
+    Span,
+    // Leave the current Coordinate Transformation Matrix untouched
+    Keep,
+    // Disable the device until one of the outputs becomes active
+    Disable,
+}
+
+impl MissingPolicy {
+    pub fn parse(s: &str) -> Option<MissingPolicy> {
+        match s {
+            "next" => Some(MissingPolicy::Next),
+            "span" => Some(MissingPolicy::Span),
+            "keep" => Some(MissingPolicy::Keep),
+            "disable" => Some(MissingPolicy::Disable),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for MissingPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = match *self {
+            MissingPolicy::Next => "next",
+            MissingPolicy::Span => "span",
+            MissingPolicy::Keep => "keep",
+            MissingPolicy::Disable => "disable",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Rule {
+    pub matcher: DeviceMatcher,
+    pub target: Target,
+    pub aspect: AspectMode,
+    pub on_missing: MissingPolicy,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FollowMode {
+    // Map to the output that contains the pointer
+    Pointer,
+    // Map to the rectangle of the focused window
+    Window,
+}
+
+impl FollowMode {
+    pub fn parse(s: &str) -> Option<FollowMode> {
+        match s {
+            "pointer" => Some(FollowMode::Pointer),
+            "window" => Some(FollowMode::Window),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Config {
+    pub watch: bool,
+    // Write the original Coordinate Transformation Matrices back when the watcher exits
+    pub restore: bool,
+    pub rules: Vec<Rule>,
+    pub follow: Option<FollowMode>,
+    pub dwell_ms: u64,
+    pub hysteresis: i32,
+    // Clip the focused window to the output it sits on (FollowMode::Window)
+    pub clip_to_output: bool,
+    pub cycle_key: Option<Hotkey>,
+    // Quiet time after RANDR and device hierarchy events before the outputs and devices are
+    // evaluated again, so that a burst of events (e.g. when docking) is handled once
+    pub debounce_ms: u64,
+}
+
+impl Config {
+    pub fn with_rules(rules: Vec<Rule>) -> Config {
+        Config {
+            watch: false,
+            restore: true,
+            rules: rules,
+            follow: None,
+            dwell_ms: DEFAULT_DWELL_MS,
+            hysteresis: DEFAULT_HYSTERESIS,
+            clip_to_output: false,
+            cycle_key: None,
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+        }
+    }
+}
+
+const DEFAULT_DWELL_MS: u64 = 300;
+const DEFAULT_HYSTERESIS: i32 = 20;
+const DEFAULT_DEBOUNCE_MS: u64 = 150;
+pub const DEFAULT_CYCLE_KEY: &'static str = "Super+Shift+T";
+
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var("HOME") {
+            Ok(home) => PathBuf::from(home).join(".config"),
+            Err(_) => return None,
+        }
+    };
+    Some(config_dir.join("wacom-output-mapping-watcher").join("config.toml"))
+}
+
+pub fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    parse_config(&text)
+}
+
+pub fn parse_config(text: &str) -> Result<Config, ConfigError> {
+    let mut parser = toml::Parser::new(text);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let messages: Vec<_> = parser.errors.iter().map(|e| {
+                let (line, col) = parser.to_linecol(e.lo);
+                format!("line {}, column {}: {}", line + 1, col + 1, e.desc)
+            }).collect();
+            return Err(ConfigError::ParseError(messages.join("; ")));
+        }
+    };
+
+    try!(check_keys(&table, &["watch", "restore", "follow", "dwell", "hysteresis", "clip-to-output", "cycle-key", "debounce", "rule"], "top level"));
+
+    let watch = try!(get_bool(&table, "watch", "top level")).unwrap_or(false);
+    let restore = try!(get_bool(&table, "restore", "top level")).unwrap_or(true);
+    let follow = match try!(get_str(&table, "follow", "top level")) {
+        Some(mode) => match FollowMode::parse(mode) {
+            Some(follow) => Some(follow),
+            None => return Err(ConfigError::LogicError("`follow` must be \"pointer\" or \"window\"".to_owned())),
+        },
+        None => None,
+    };
+    let dwell_ms = try!(get_int(&table, "dwell", "top level")).unwrap_or(DEFAULT_DWELL_MS as i64);
+    let hysteresis = try!(get_int(&table, "hysteresis", "top level")).unwrap_or(DEFAULT_HYSTERESIS as i64);
+    try!(check_non_negative("dwell", dwell_ms).map_err(ConfigError::LogicError));
+    try!(check_non_negative("hysteresis", hysteresis).map_err(ConfigError::LogicError));
+    let clip_to_output = try!(get_bool(&table, "clip-to-output", "top level")).unwrap_or(false);
+    let cycle_key = match try!(get_str(&table, "cycle-key", "top level")) {
+        Some(spec) => match Hotkey::parse(spec) {
+            Ok(hotkey) => Some(hotkey),
+            Err(e) => return Err(ConfigError::LogicError(e)),
+        },
+        None => None,
+    };
+    let debounce_ms = try!(get_int(&table, "debounce", "top level")).unwrap_or(DEFAULT_DEBOUNCE_MS as i64);
+    try!(check_non_negative("debounce", debounce_ms).map_err(ConfigError::LogicError));
+
+    let rule_values = match table.get("rule") {
+        Some(&toml::Value::Array(ref values)) => &values[..],
+        Some(_) => return Err(ConfigError::LogicError("`rule` must be an array of tables ([[rule]])".to_owned())),
+        None => &[][..],
+    };
+
+    let mut rules = vec!();
+    for (idx, value) in rule_values.iter().enumerate() {
+        let context = format!("rule #{}", idx + 1);
+        let rule_table = match value.as_table() {
+            Some(t) => t,
+            None => return Err(ConfigError::LogicError(format!("{} must be a table", context))),
+        };
+        rules.push(try!(parse_rule(rule_table, &context)));
+    }
+
+    if rules.is_empty() {
+        return Err(ConfigError::LogicError("no rules are defined".to_owned()));
+    }
+
+    Ok(Config {
+        watch: watch || follow.is_some(),
+        restore: restore,
+        rules: rules,
+        follow: follow,
+        dwell_ms: dwell_ms as u64,
+        hysteresis: hysteresis as i32,
+        clip_to_output: clip_to_output,
+        cycle_key: cycle_key,
+        debounce_ms: debounce_ms as u64,
+    })
+}
+
+fn parse_rule(table: &toml::Table, context: &str) -> Result<Rule, ConfigError> {
+    try!(check_keys(table, &["include", "exclude", "output", "area", "keep-aspect", "on-missing"], context));
+
+    let output = match table.get("output") {
+        Some(&toml::Value::String(ref s)) => Some(s.split(',').map(|s| s.to_owned()).collect()),
+        _ => try!(get_str_list(table, "output", context)),
+    };
+    let target = match (output, try!(get_str(table, "area", context))) {
+        (Some(output), None) => match OutputSelector::parse_list(&output) {
+            Ok(selectors) => Target::Output(selectors),
+            Err(e) => return Err(ConfigError::LogicError(format!("{}: {}", context, e))),
+        },
+        (None, Some(area)) => match Geometry::parse(area) {
+            Ok(geometry) => Target::Area(geometry),
+            Err(e) => return Err(ConfigError::LogicError(format!("{}: {}", context, e))),
+        },
+        (Some(_), Some(_)) => return Err(ConfigError::LogicError(format!("{}: `output` and `area` are mutually exclusive", context))),
+        (None, None) => return Err(ConfigError::LogicError(format!("{}: either `output` or `area` is required", context))),
+    };
+    let include = try!(get_str_list(table, "include", context)).unwrap_or(vec!());
+    let exclude = try!(get_str_list(table, "exclude", context)).unwrap_or(vec!());
+    let matcher = match DeviceMatcher::parse(&include, &exclude) {
+        Ok(matcher) => matcher,
+        Err(e) => return Err(ConfigError::LogicError(format!("{}: {}", context, e))),
+    };
+
+    let aspect = match try!(get_str(table, "keep-aspect", context)) {
+        Some(mode) => match AspectMode::parse(mode) {
+            Some(aspect) => aspect,
+            None => return Err(ConfigError::LogicError(format!("{}: `keep-aspect` must be one of stretch, crop, letterbox", context))),
+        },
+        None => AspectMode::Stretch,
+    };
+    let on_missing = match try!(get_str(table, "on-missing", context)) {
+        Some(policy) => match MissingPolicy::parse(policy) {
+            Some(on_missing) => on_missing,
+            None => return Err(ConfigError::LogicError(format!("{}: `on-missing` must be one of next, span, keep, disable", context))),
+        },
+        None => MissingPolicy::Next,
+    };
+
+    Ok(Rule {
+        matcher: matcher,
+        target: target,
+        aspect: aspect,
+        on_missing: on_missing,
+    })
+}
+
+// Range check of the settings that can be given both in the file and on the command line
+pub fn check_non_negative(key: &str, value: i64) -> Result<(), String> {
+    if value < 0 {
+        Err(format!("`{}` must not be negative", key))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_keys(table: &toml::Table, allowed: &[&str], context: &str) -> Result<(), ConfigError> {
+    for key in table.keys() {
+        if !allowed.contains(&&key[..]) {
+            return Err(ConfigError::LogicError(format!("{}: unknown key `{}`", context, key)));
+        }
+    }
+    Ok(())
+}
+
+fn get_str<'a>(table: &'a toml::Table, key: &str, context: &str) -> Result<Option<&'a str>, ConfigError> {
+    match table.get(key) {
+        Some(value) => match value.as_str() {
+            Some(s) => Ok(Some(s)),
+            None => Err(ConfigError::LogicError(format!("{}: `{}` must be a string", context, key))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn get_str_list(table: &toml::Table, key: &str, context: &str) -> Result<Option<Vec<String>>, ConfigError> {
+    let values = match table.get(key) {
+        Some(value) => match value.as_slice() {
+            Some(values) => values,
+            None => return Err(ConfigError::LogicError(format!("{}: `{}` must be an array of strings", context, key))),
+        },
+        None => return Ok(None),
+    };
+    let mut result = vec!();
+    for value in values.iter() {
+        match value.as_str() {
+            Some(s) => result.push(s.to_owned()),
+            None => return Err(ConfigError::LogicError(format!("{}: `{}` must be an array of strings", context, key))),
+        }
+    }
+    Ok(Some(result))
+}
+
+fn get_int(table: &toml::Table, key: &str, context: &str) -> Result<Option<i64>, ConfigError> {
+    match table.get(key) {
+        Some(value) => match value.as_integer() {
+            Some(i) => Ok(Some(i)),
+            None => Err(ConfigError::LogicError(format!("{}: `{}` must be an integer", context, key))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn get_bool(table: &toml::Table, key: &str, context: &str) -> Result<Option<bool>, ConfigError> {
+    match table.get(key) {
+        Some(value) => match value.as_bool() {
+            Some(b) => Ok(Some(b)),
+            None => Err(ConfigError::LogicError(format!("{}: `{}` must be a boolean", context, key))),
+        },
+        None => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapping::AspectMode;
+    use outputs::OutputSelector;
+
+    fn error_message(text: &str) -> String {
+        match parse_config(text) {
+            Ok(_) => panic!("`{}` was accepted", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_rules_and_defaults() {
+        let config = parse_config(r#"
+            watch = true
+            [[rule]]
+            include = ["name:Intuos"]
+            output = "HDMI-1,DP-1"
+            keep-aspect = "letterbox"
+            [[rule]]
+            area = "50%x100%+0+0"
+        "#).unwrap();
+        assert!(config.watch);
+        assert!(config.restore);
+        assert_eq!(config.debounce_ms, DEFAULT_DEBOUNCE_MS);
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(config.rules[0].target, Target::Output(vec!(OutputSelector::Name("HDMI-1".to_owned()), OutputSelector::Name("DP-1".to_owned()))));
+        assert_eq!(config.rules[0].aspect, AspectMode::Letterbox);
+        assert_eq!(config.rules[0].on_missing, MissingPolicy::Next);
+        assert_eq!(config.rules[1].target.to_string(), "area 50%x100%+0+0");
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(error_message("wacth = true\n[[rule]]\noutput = \"HDMI-1\""), "top level: unknown key `wacth`");
+        assert_eq!(error_message("[[rule]]\noutput = \"HDMI-1\"\noutptu = \"DP-1\""), "rule #1: unknown key `outptu`");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(error_message("watch = true"), "no rules are defined");
+        assert_eq!(error_message("[[rule]]\ninclude = [\"name:Wacom\"]"), "rule #1: either `output` or `area` is required");
+        assert_eq!(error_message("[[rule]]\noutput = \"HDMI-1\"\narea = \"100x100\""), "rule #1: `output` and `area` are mutually exclusive");
+        assert_eq!(error_message("debounce = -1\n[[rule]]\noutput = \"HDMI-1\""), "`debounce` must not be negative");
+        assert_eq!(error_message("hysteresis = -20\n[[rule]]\noutput = \"HDMI-1\""), "`hysteresis` must not be negative");
+        assert_eq!(error_message("watch = \"yes\"\n[[rule]]\noutput = \"HDMI-1\""), "top level: `watch` must be a boolean");
+        assert!(error_message("[[rule]\n").starts_with("parse error: line 1"));
+    }
+}
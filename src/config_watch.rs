@@ -0,0 +1,144 @@
+use libc::c_int;
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::slice;
+use sys;
+
+// Notices changes to the configuration file through inotify. The directory is watched rather
+// than the file, because editors usually replace the file instead of writing to it. When the
+// path is a symlink, the directory of the file it points to is watched as well.
+pub struct ConfigWatcher {
+    inotify: File,
+    path: PathBuf,
+    // Watch descriptor of each directory and the name of the file in it
+    watches: Vec<(c_int, OsString)>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> io::Result<ConfigWatcher> {
+        let fd = unsafe { sys::inotify_init1(sys::IN_NONBLOCK | sys::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut watcher = ConfigWatcher {
+            inotify: unsafe { File::from_raw_fd(fd) },
+            path: path.to_path_buf(),
+            watches: vec!(),
+        };
+        try!(watcher.watch_directory(path));
+        watcher.watch_target();
+        Ok(watcher)
+    }
+
+    fn watch_directory(&mut self, path: &Path) -> io::Result<()> {
+        let file_name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file")),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = match CString::new(dir.as_os_str().as_bytes()) {
+            Ok(dir) => dir,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte")),
+        };
+        let mask = sys::IN_CLOSE_WRITE | sys::IN_MOVED_TO | sys::IN_CREATE | sys::IN_DELETE;
+        let wd = unsafe { sys::inotify_add_watch(self.inotify.as_raw_fd(), dir.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if !self.watches.iter().any(|&(w, ref name)| w == wd && *name == file_name) {
+            self.watches.push((wd, file_name));
+        }
+        Ok(())
+    }
+
+    // Also watches the file the path resolves to, which changes when the symlink is replaced
+    fn watch_target(&mut self) {
+        let target = match fs::canonicalize(&self.path) {
+            Ok(target) => target,
+            Err(_) => return,
+        };
+        if let Err(e) = self.watch_directory(&target) {
+            warn!("Failed to watch {} for changes: {}", target.display(), e);
+        }
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.inotify.as_raw_fd()
+    }
+
+    // True if the file has changed since the last call. Never blocks.
+    pub fn poll_changed(&mut self) -> bool {
+        let header_len = mem::size_of::<sys::inotify_event>();
+        // u32 items, so that the event headers are aligned
+        let mut buffer = [0u32; 1024];
+        let bytes = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 4) };
+        let mut changed = false;
+        loop {
+            let len = match self.inotify.read(bytes) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to read inotify events: {}", e);
+                    break;
+                }
+            };
+            let mut pos = 0;
+            while pos + header_len <= len {
+                let event = unsafe { &*(bytes[pos..].as_ptr() as *const sys::inotify_event) };
+                let name = &bytes[pos + header_len..pos + header_len + event.len as usize];
+                let name_len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                let watched = self.watches.iter().any(|&(wd, ref file_name)| wd == event.wd && &name[..name_len] == file_name.as_bytes());
+                if event.mask & sys::IN_Q_OVERFLOW != 0 || watched {
+                    changed = true;
+                }
+                pos += header_len + event.len as usize;
+            }
+        }
+        if changed {
+            self.watch_target();
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn notices_changes_through_symlinks() {
+        let root = env::temp_dir().join(format!("wacom-output-mapping-watcher-test-{}", unsafe { sys::getpid() }));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("store")).unwrap();
+        fs::create_dir_all(root.join("config")).unwrap();
+        File::create(root.join("store/config.toml")).unwrap();
+        symlink(root.join("store/config.toml"), root.join("config/config.toml")).unwrap();
+
+        let mut watcher = ConfigWatcher::new(&root.join("config/config.toml")).unwrap();
+        assert!(!watcher.poll_changed());
+        File::create(root.join("store/other.toml")).unwrap();
+        assert!(!watcher.poll_changed());
+        File::create(root.join("store/config.toml")).unwrap().write_all(b"watch = true\n").unwrap();
+        assert!(watcher.poll_changed());
+        assert!(!watcher.poll_changed());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
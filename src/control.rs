@@ -0,0 +1,273 @@
+use libc::c_int;
+use config::Target;
+use outputs::OutputSelector;
+use std::borrow::ToOwned;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+const MAX_COMMAND_LEN: usize = 4096;
+const IO_TIMEOUT_MS: u64 = 5000;
+// Connections that have not sent a full command yet; further ones are refused
+const MAX_PENDING_CLIENTS: usize = 16;
+
+// Commands accepted on the control socket. A client sends one command per connection as a
+// line of space-separated words; the reply is `ok` or `error MESSAGE`, followed by the
+// lines of the result, if any.
+#[derive(Debug,Clone,PartialEq)]
+pub enum ControlCommand {
+    // Target that takes precedence over the rules until Reset
+    SetTarget(Target),
+    Cycle,
+    Status,
+    Reapply,
+    Reset,
+    Reload,
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<ControlCommand, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Err("empty command".to_owned()),
+        };
+        let command = match name {
+            "set-output" => return match args.len() {
+                1 => OutputSelector::parse_list(&args[0].split(',').collect::<Vec<_>>())
+                    .map(|selectors| ControlCommand::SetTarget(Target::Output(selectors))),
+                _ => Err("usage: set-output OUTPUT[,OUTPUT...]".to_owned()),
+            },
+            "cycle" => ControlCommand::Cycle,
+            "status" => ControlCommand::Status,
+            "reapply" => ControlCommand::Reapply,
+            "reset" => ControlCommand::Reset,
+            "reload" => ControlCommand::Reload,
+            _ => return Err(format!("unknown command `{}`", name)),
+        };
+        if !args.is_empty() {
+            return Err(format!("{} takes no arguments", name));
+        }
+        Ok(command)
+    }
+}
+
+// $XDG_RUNTIME_DIR/wacom-output-mapping-watcher-$DISPLAY.sock, None without XDG_RUNTIME_DIR
+pub fn socket_path() -> Option<PathBuf> {
+    let runtime_dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => return None,
+    };
+    // DISPLAY may be a path (e.g. with launchd), which cannot be part of a file name
+    let display = env::var("DISPLAY").unwrap_or(String::new()).replace('/', "_");
+    Some(runtime_dir.join(format!("wacom-output-mapping-watcher-{}.sock", display)))
+}
+
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    // Connections whose command has not been received in full yet, with the time they were
+    // accepted; they are dropped after IO_TIMEOUT_MS
+    clients: Vec<(UnixStream, Vec<u8>, Instant)>,
+}
+
+// Command received from a client, which waits for the reply
+pub struct ControlRequest {
+    stream: UnixStream,
+    pub command: Result<ControlCommand, String>,
+}
+
+impl ControlServer {
+    pub fn bind(path: PathBuf) -> io::Result<ControlServer> {
+        // A socket nobody listens on anymore was left behind by a watcher that was killed
+        if path.exists() && UnixStream::connect(&path).is_err() {
+            try!(fs::remove_file(&path));
+        }
+        let listener = try!(UnixListener::bind(&path));
+        try!(listener.set_nonblocking(true));
+        Ok(ControlServer { listener: listener, path: path, clients: vec!() })
+    }
+
+    // Descriptors the event loop has to wait on, in addition to the X connection
+    pub fn fds(&self) -> Vec<c_int> {
+        let mut fds = vec!(self.listener.as_raw_fd());
+        fds.extend(self.clients.iter().map(|&(ref stream, _, _)| stream.as_raw_fd()));
+        fds
+    }
+
+    // Time until the oldest pending connection times out
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let timeout = Duration::from_millis(IO_TIMEOUT_MS);
+        self.clients.iter().map(|&(_, _, accepted)| {
+            let elapsed = accepted.elapsed();
+            if elapsed < timeout { timeout - elapsed } else { Duration::from_millis(0) }
+        }).min()
+    }
+
+    // Accepts new connections and returns the commands received in full so far. Never blocks.
+    pub fn poll_requests(&mut self) -> Vec<ControlRequest> {
+        loop {
+            match self.listener.accept() {
+                Ok((_, _)) if self.clients.len() >= MAX_PENDING_CLIENTS => {
+                    debug!("Refusing a control connection: {} are waiting for a command already", self.clients.len());
+                },
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push((stream, vec!(), Instant::now())),
+                    Err(e) => warn!("Failed to set up a control connection: {}", e),
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept a control connection: {}", e);
+                    break;
+                }
+            }
+        }
+        let mut requests = vec!();
+        let mut pending = vec!();
+        for (mut stream, mut buffer, accepted) in self.clients.drain(..) {
+            match read_line(&mut stream, &mut buffer) {
+                Ok(true) => {
+                    let line = String::from_utf8_lossy(&buffer).into_owned();
+                    requests.push(ControlRequest { stream: stream, command: ControlCommand::parse(&line) });
+                },
+                Ok(false) if accepted.elapsed() >= Duration::from_millis(IO_TIMEOUT_MS) => {
+                    debug!("Dropping a control connection that sent no command within {} ms", IO_TIMEOUT_MS);
+                },
+                Ok(false) => pending.push((stream, buffer, accepted)),
+                Err(e) => debug!("Dropping a control connection: {}", e),
+            }
+        }
+        self.clients = pending;
+        requests
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl ControlRequest {
+    pub fn reply(mut self, result: Result<String, String>) {
+        let reply = match result {
+            Ok(ref body) if body.is_empty() => "ok\n".to_owned(),
+            Ok(body) => format!("ok\n{}\n", body),
+            Err(message) => format!("error {}\n", message),
+        };
+        let written = self.stream.set_nonblocking(false)
+            .and_then(|_| self.stream.set_write_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS))))
+            .and_then(|_| self.stream.write_all(reply.as_bytes()));
+        if let Err(e) = written {
+            debug!("Failed to reply to a control connection: {}", e);
+        }
+    }
+}
+
+// Appends what can be read without blocking to `buffer`. Returns true once it holds a full
+// line (without the newline), or everything the client sent before closing its end.
+fn read_line(stream: &mut UnixStream, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 256];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(len) => {
+                buffer.extend(chunk[..len].iter().cloned());
+                if let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                    buffer.truncate(end);
+                    return Ok(true);
+                }
+                if buffer.len() > MAX_COMMAND_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "command too long"));
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Client side, used by the `ctl` subcommand: sends `words` as one command and returns the
+// result lines, or the error reported by the watcher
+pub fn send_command(words: &[String]) -> Result<String, String> {
+    let path = match socket_path() {
+        Some(path) => path,
+        None => return Err("XDG_RUNTIME_DIR is not set".to_owned()),
+    };
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(e) => return Err(format!("Failed to connect to {} (is the watcher running?): {}", path.display(), e)),
+    };
+    let mut reply = String::new();
+    let exchanged = stream.set_read_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS)))
+        .and_then(|_| stream.write_all(format!("{}\n", words.join(" ")).as_bytes()))
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read_to_string(&mut reply));
+    if let Err(e) = exchanged {
+        return Err(format!("Failed to talk to the watcher: {}", e));
+    }
+    let mut lines = reply.splitn(2, '\n');
+    match lines.next() {
+        Some("ok") => Ok(lines.next().unwrap_or("").trim_right().to_owned()),
+        Some(status) if status.starts_with("error ") => Err(status["error ".len()..].to_owned()),
+        _ => Err(format!("Unexpected reply from the watcher: {:?}", reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Target;
+    use outputs::OutputSelector;
+    use std::borrow::ToOwned;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(ControlCommand::parse("cycle"), Ok(ControlCommand::Cycle));
+        assert_eq!(ControlCommand::parse("  status \t"), Ok(ControlCommand::Status));
+        assert_eq!(ControlCommand::parse("reapply"), Ok(ControlCommand::Reapply));
+        assert_eq!(ControlCommand::parse("reset"), Ok(ControlCommand::Reset));
+        assert_eq!(ControlCommand::parse("reload"), Ok(ControlCommand::Reload));
+        assert_eq!(ControlCommand::parse("set-output DP-1,primary"), Ok(ControlCommand::SetTarget(Target::Output(vec!(
+            OutputSelector::Name("DP-1".to_owned()),
+            OutputSelector::Primary,
+        )))));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert_eq!(ControlCommand::parse(""), Err("empty command".to_owned()));
+        assert_eq!(ControlCommand::parse("fly"), Err("unknown command `fly`".to_owned()));
+        assert_eq!(ControlCommand::parse("cycle now"), Err("cycle takes no arguments".to_owned()));
+        assert_eq!(ControlCommand::parse("set-output"), Err("usage: set-output OUTPUT[,OUTPUT...]".to_owned()));
+        assert_eq!(ControlCommand::parse("set-output DP-1 HDMI-1"), Err("usage: set-output OUTPUT[,OUTPUT...]".to_owned()));
+        assert!(ControlCommand::parse("set-output DP-1,,HDMI-1").is_err());
+    }
+
+    #[test]
+    fn reads_lines_up_to_the_limit() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let mut buffer = vec!();
+        assert!(!read_line(&mut server, &mut buffer).unwrap());
+        client.write_all(b"set-out").unwrap();
+        assert!(!read_line(&mut server, &mut buffer).unwrap());
+        client.write_all(b"put DP-1\nignored").unwrap();
+        assert!(read_line(&mut server, &mut buffer).unwrap());
+        assert_eq!(buffer, b"set-output DP-1".to_vec());
+
+        let mut buffer = vec!();
+        client.write_all(&vec!(b'x'; MAX_COMMAND_LEN + 1)).unwrap();
+        assert!(read_line(&mut server, &mut buffer).is_err());
+    }
+}
@@ -0,0 +1,1017 @@
+// Minimal D-Bus client: enough of the wire protocol to own a name on the session bus,
+// answer method calls and emit signals. Only `unix:path=` and `unix:abstract=` addresses are
+// supported and no file descriptors are passed. The dbus crate would add libdbus-1 to the
+// C libraries the watcher links against, and the pure Rust implementations need far newer
+// compilers and dependencies than the rest of the watcher.
+use libc::c_char;
+use libc::c_int;
+use libc::c_uint;
+use libc::c_ushort;
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::result::Result;
+use std::str;
+use sys;
+
+const BUS_NAME: &'static str = "org.freedesktop.DBus";
+const BUS_PATH: &'static str = "/org/freedesktop/DBus";
+// Largest message allowed by the specification
+const MAX_MESSAGE_LEN: usize = 128 * 1024 * 1024;
+// Deepest nesting allowed by the specification, which also keeps a peer from overflowing the
+// stack of the reader
+const MAX_ARRAY_DEPTH: u32 = 32;
+const MAX_STRUCT_DEPTH: u32 = 32;
+const MAX_TOTAL_DEPTH: u32 = 64;
+
+pub const NO_REPLY_EXPECTED: u8 = 0x1;
+
+#[derive(Debug)]
+pub enum DBusError {
+    IOError(io::Error),
+    ProtoError(String),
+    // Error reply to a method call: error name and message
+    MethodError(String, String),
+}
+
+impl From<io::Error> for DBusError {
+    fn from(err: io::Error) -> DBusError {
+        DBusError::IOError(err)
+    }
+}
+
+impl fmt::Display for DBusError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DBusError::IOError(ref e) => write!(fmt, "{}", e),
+            DBusError::ProtoError(ref s) => write!(fmt, "protocol error: {}", s),
+            DBusError::MethodError(ref name, ref message) => write!(fmt, "{}: {}", name, message),
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    Str(String),
+    ObjectPath(String),
+    Signature(String),
+    // Signature of the elements, which empty arrays need as well
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    pub fn signature(&self) -> String {
+        match *self {
+            Value::Byte(_) => "y".to_owned(),
+            Value::Bool(_) => "b".to_owned(),
+            Value::Int16(_) => "n".to_owned(),
+            Value::UInt16(_) => "q".to_owned(),
+            Value::Int32(_) => "i".to_owned(),
+            Value::UInt32(_) => "u".to_owned(),
+            Value::Int64(_) => "x".to_owned(),
+            Value::UInt64(_) => "t".to_owned(),
+            Value::Double(_) => "d".to_owned(),
+            Value::Str(_) => "s".to_owned(),
+            Value::ObjectPath(_) => "o".to_owned(),
+            Value::Signature(_) => "g".to_owned(),
+            Value::Array(ref element, _) => format!("a{}", element),
+            Value::Struct(ref fields) => format!("({})", fields.iter().map(|f| f.signature()).collect::<String>()),
+            Value::DictEntry(ref key, ref value) => format!("{{{}{}}}", key.signature(), value.signature()),
+            Value::Variant(_) => "v".to_owned(),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Str(ref s) | Value::ObjectPath(ref s) | Value::Signature(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match *self {
+            Value::UInt32(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn string_array(items: &[String]) -> Value {
+        Value::Array("s".to_owned(), items.iter().map(|s| Value::Str(s.clone())).collect())
+    }
+
+    // a{sv}, e.g. for org.freedesktop.DBus.Properties
+    pub fn variant_dict(entries: Vec<(&str, Value)>) -> Value {
+        let entries = entries.into_iter()
+            .map(|(key, value)| Value::DictEntry(Box::new(Value::Str(key.to_owned())), Box::new(Value::Variant(Box::new(value)))))
+            .collect();
+        Value::Array("{sv}".to_owned(), entries)
+    }
+}
+
+fn alignment(type_code: u8) -> usize {
+    match type_code {
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b's' | b'o' | b'a' => 4,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 1,
+    }
+}
+
+// Length of the single complete type at the start of `signature`
+fn single_type_len(signature: &[u8]) -> Result<usize, DBusError> {
+    match signature.first() {
+        Some(&b'a') => Ok(1 + try!(single_type_len(&signature[1..]))),
+        Some(&open) if open == b'(' || open == b'{' => {
+            let close = if open == b'(' { b')' } else { b'}' };
+            let mut len = 1;
+            loop {
+                match signature.get(len) {
+                    Some(&c) if c == close => return Ok(len + 1),
+                    Some(_) => len += try!(single_type_len(&signature[len..])),
+                    None => return Err(DBusError::ProtoError("unterminated container in signature".to_owned())),
+                }
+            }
+        },
+        Some(c) if b"ybnqiuxtdsogv".contains(c) => Ok(1),
+        Some(c) => Err(DBusError::ProtoError(format!("unsupported type `{}` in signature", *c as char))),
+        None => Err(DBusError::ProtoError("empty signature".to_owned())),
+    }
+}
+
+fn pad(buf: &mut Vec<u8>, align: usize) {
+    while buf.len() % align != 0 {
+        buf.push(0);
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend((0..2).map(|i| (v >> (i * 8)) as u8));
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend((0..4).map(|i| (v >> (i * 8)) as u8));
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend((0..8).map(|i| (v >> (i * 8)) as u8));
+}
+
+// Appends `value` in little-endian wire format. `buf` must start at an 8-byte boundary of
+// the message, which holds for the header and for the body.
+fn marshal(buf: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Byte(v) => buf.push(v),
+        Value::Bool(v) => { pad(buf, 4); put_u32(buf, v as u32) },
+        Value::Int16(v) => { pad(buf, 2); put_u16(buf, v as u16) },
+        Value::UInt16(v) => { pad(buf, 2); put_u16(buf, v) },
+        Value::Int32(v) => { pad(buf, 4); put_u32(buf, v as u32) },
+        Value::UInt32(v) => { pad(buf, 4); put_u32(buf, v) },
+        Value::Int64(v) => { pad(buf, 8); put_u64(buf, v as u64) },
+        Value::UInt64(v) => { pad(buf, 8); put_u64(buf, v) },
+        Value::Double(v) => { pad(buf, 8); put_u64(buf, unsafe { mem::transmute::<f64, u64>(v) }) },
+        Value::Str(ref s) | Value::ObjectPath(ref s) => {
+            pad(buf, 4);
+            put_u32(buf, s.len() as u32);
+            buf.extend(s.as_bytes().iter().cloned());
+            buf.push(0);
+        },
+        Value::Signature(ref s) => {
+            buf.push(s.len() as u8);
+            buf.extend(s.as_bytes().iter().cloned());
+            buf.push(0);
+        },
+        Value::Array(ref element, ref items) => {
+            pad(buf, 4);
+            let len_pos = buf.len();
+            put_u32(buf, 0);
+            // The padding before the first element is not part of the array length
+            pad(buf, alignment(element.as_bytes()[0]));
+            let start = buf.len();
+            for item in items.iter() {
+                marshal(buf, item);
+            }
+            let len = (buf.len() - start) as u32;
+            for i in 0..4 {
+                buf[len_pos + i] = (len >> (i * 8)) as u8;
+            }
+        },
+        Value::Struct(ref fields) => {
+            pad(buf, 8);
+            for field in fields.iter() {
+                marshal(buf, field);
+            }
+        },
+        Value::DictEntry(ref key, ref value) => {
+            pad(buf, 8);
+            marshal(buf, key);
+            marshal(buf, value);
+        },
+        Value::Variant(ref value) => {
+            marshal(buf, &Value::Signature(value.signature()));
+            marshal(buf, value);
+        },
+    }
+}
+
+// Containers around the value being read. Dict entries count as structs; variants only count
+// towards the total.
+#[derive(Clone,Copy,Default)]
+struct Nesting {
+    arrays: u32,
+    structs: u32,
+    total: u32,
+}
+
+impl Nesting {
+    fn enter(self, container: u8) -> Result<Nesting, DBusError> {
+        let mut nesting = self;
+        nesting.total += 1;
+        match container {
+            b'a' => nesting.arrays += 1,
+            b'(' | b'{' => nesting.structs += 1,
+            _ => {},
+        }
+        if nesting.arrays > MAX_ARRAY_DEPTH || nesting.structs > MAX_STRUCT_DEPTH || nesting.total > MAX_TOTAL_DEPTH {
+            return Err(DBusError::ProtoError("containers are nested too deeply".to_owned()));
+        }
+        Ok(nesting)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, align: usize) -> Result<(), DBusError> {
+        self.pos = (self.pos + align - 1) / align * align;
+        if self.pos > self.data.len() {
+            return Err(DBusError::ProtoError("truncated message".to_owned()));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DBusError> {
+        if self.data.len() - self.pos < len {
+            return Err(DBusError::ProtoError("truncated message".to_owned()));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, DBusError> {
+        try!(self.align(len));
+        let bytes = try!(self.take(len));
+        let ordered: Vec<u8> = if self.big_endian { bytes.iter().rev().cloned().collect() } else { bytes.to_vec() };
+        Ok(ordered.iter().enumerate().fold(0, |v, (i, b)| v | (*b as u64) << (i * 8)))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, DBusError> {
+        let bytes = try!(self.take(len + 1));
+        match String::from_utf8(bytes[..len].to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(DBusError::ProtoError("invalid UTF-8 in string".to_owned())),
+        }
+    }
+
+    // `signature` must start with a single complete type
+    fn value(&mut self, signature: &[u8]) -> Result<Value, DBusError> {
+        self.nested_value(signature, Nesting::default())
+    }
+
+    fn nested_value(&mut self, signature: &[u8], nesting: Nesting) -> Result<Value, DBusError> {
+        Ok(match signature[0] {
+            b'y' => Value::Byte(try!(self.take(1))[0]),
+            b'b' => Value::Bool(try!(self.uint(4)) != 0),
+            b'n' => Value::Int16(try!(self.uint(2)) as i16),
+            b'q' => Value::UInt16(try!(self.uint(2)) as u16),
+            b'i' => Value::Int32(try!(self.uint(4)) as i32),
+            b'u' => Value::UInt32(try!(self.uint(4)) as u32),
+            b'x' => Value::Int64(try!(self.uint(8)) as i64),
+            b't' => Value::UInt64(try!(self.uint(8))),
+            b'd' => Value::Double(unsafe { mem::transmute::<u64, f64>(try!(self.uint(8))) }),
+            b's' => {
+                let len = try!(self.uint(4)) as usize;
+                Value::Str(try!(self.string(len)))
+            },
+            b'o' => {
+                let len = try!(self.uint(4)) as usize;
+                Value::ObjectPath(try!(self.string(len)))
+            },
+            b'g' => {
+                let len = try!(self.take(1))[0] as usize;
+                Value::Signature(try!(self.string(len)))
+            },
+            b'a' => {
+                let len = try!(self.uint(4)) as usize;
+                let element = &signature[1..1 + try!(single_type_len(&signature[1..]))];
+                try!(self.align(alignment(element[0])));
+                if self.data.len() - self.pos < len {
+                    return Err(DBusError::ProtoError("truncated array".to_owned()));
+                }
+                let end = self.pos + len;
+                let inner = try!(nesting.enter(b'a'));
+                let mut items = vec!();
+                while self.pos < end {
+                    items.push(try!(self.nested_value(element, inner)));
+                }
+                Value::Array(String::from_utf8_lossy(element).into_owned(), items)
+            },
+            b'(' => {
+                try!(self.align(8));
+                let inner = try!(nesting.enter(b'('));
+                let mut fields = vec!();
+                let mut rest = &signature[1..];
+                while rest[0] != b')' {
+                    let len = try!(single_type_len(rest));
+                    fields.push(try!(self.nested_value(&rest[..len], inner)));
+                    rest = &rest[len..];
+                }
+                Value::Struct(fields)
+            },
+            b'{' => {
+                try!(self.align(8));
+                let inner = try!(nesting.enter(b'{'));
+                let key_len = try!(single_type_len(&signature[1..]));
+                let key = try!(self.nested_value(&signature[1..1 + key_len], inner));
+                let value = try!(self.nested_value(&signature[1 + key_len..], inner));
+                Value::DictEntry(Box::new(key), Box::new(value))
+            },
+            b'v' => {
+                let len = try!(self.take(1))[0] as usize;
+                let inner = try!(self.string(len));
+                if try!(single_type_len(inner.as_bytes())) != inner.len() {
+                    return Err(DBusError::ProtoError("variant must hold a single type".to_owned()));
+                }
+                Value::Variant(Box::new(try!(self.nested_value(inner.as_bytes(), try!(nesting.enter(b'v'))))))
+            },
+            c => return Err(DBusError::ProtoError(format!("unsupported type `{}`", c as char))),
+        })
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MessageType {
+    MethodCall = 1,
+    MethodReturn = 2,
+    Error = 3,
+    Signal = 4,
+}
+
+#[derive(Debug,Clone)]
+pub struct Message {
+    pub message_type: MessageType,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    fn new(message_type: MessageType) -> Message {
+        Message {
+            message_type: message_type,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body: vec!(),
+        }
+    }
+
+    pub fn method_call(destination: &str, path: &str, interface: &str, member: &str, body: Vec<Value>) -> Message {
+        let mut message = Message::new(MessageType::MethodCall);
+        message.destination = Some(destination.to_owned());
+        message.path = Some(path.to_owned());
+        message.interface = Some(interface.to_owned());
+        message.member = Some(member.to_owned());
+        message.body = body;
+        message
+    }
+
+    pub fn signal(path: &str, interface: &str, member: &str, body: Vec<Value>) -> Message {
+        let mut message = Message::new(MessageType::Signal);
+        message.path = Some(path.to_owned());
+        message.interface = Some(interface.to_owned());
+        message.member = Some(member.to_owned());
+        message.body = body;
+        message
+    }
+
+    pub fn method_return(call: &Message, body: Vec<Value>) -> Message {
+        let mut message = Message::new(MessageType::MethodReturn);
+        message.reply_serial = Some(call.serial);
+        message.destination = call.sender.clone();
+        message.body = body;
+        message
+    }
+
+    pub fn error(call: &Message, name: &str, text: &str) -> Message {
+        let mut message = Message::new(MessageType::Error);
+        message.error_name = Some(name.to_owned());
+        message.reply_serial = Some(call.serial);
+        message.destination = call.sender.clone();
+        message.body = vec!(Value::Str(text.to_owned()));
+        message
+    }
+
+    pub fn expects_reply(&self) -> bool {
+        self.message_type == MessageType::MethodCall && self.flags & NO_REPLY_EXPECTED == 0
+    }
+
+    pub fn signature(&self) -> String {
+        self.body.iter().map(|v| v.signature()).collect()
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        let mut body = vec!();
+        for value in self.body.iter() {
+            marshal(&mut body, value);
+        }
+
+        let field = |code: u8, value: Value| Value::Struct(vec!(Value::Byte(code), Value::Variant(Box::new(value))));
+        let mut fields = vec!();
+        if let Some(ref path) = self.path {
+            fields.push(field(1, Value::ObjectPath(path.clone())));
+        }
+        if let Some(ref interface) = self.interface {
+            fields.push(field(2, Value::Str(interface.clone())));
+        }
+        if let Some(ref member) = self.member {
+            fields.push(field(3, Value::Str(member.clone())));
+        }
+        if let Some(ref error_name) = self.error_name {
+            fields.push(field(4, Value::Str(error_name.clone())));
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            fields.push(field(5, Value::UInt32(reply_serial)));
+        }
+        if let Some(ref destination) = self.destination {
+            fields.push(field(6, Value::Str(destination.clone())));
+        }
+        if !self.body.is_empty() {
+            fields.push(field(8, Value::Signature(self.signature())));
+        }
+
+        let mut buf = vec!(b'l', self.message_type as u8, self.flags, 1);
+        put_u32(&mut buf, body.len() as u32);
+        put_u32(&mut buf, self.serial);
+        marshal(&mut buf, &Value::Array("(yv)".to_owned(), fields));
+        pad(&mut buf, 8);
+        buf.extend(body);
+        buf
+    }
+
+    // Length of the message whose first 16 bytes are `header`
+    fn wire_len(header: &[u8]) -> Result<usize, DBusError> {
+        let mut reader = Reader { data: header, pos: 4, big_endian: header[0] == b'B' };
+        let body_len = try!(reader.uint(4)) as usize;
+        reader.pos = 12;
+        let fields_len = try!(reader.uint(4)) as usize;
+        let len = (16 + fields_len + 7) / 8 * 8 + body_len;
+        if len > MAX_MESSAGE_LEN {
+            return Err(DBusError::ProtoError(format!("message of {} bytes is too long", len)));
+        }
+        Ok(len)
+    }
+
+    fn unmarshal(data: &[u8]) -> Result<Message, DBusError> {
+        let big_endian = match data[0] {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err(DBusError::ProtoError("invalid byte order".to_owned())),
+        };
+        let message_type = match data[1] {
+            1 => MessageType::MethodCall,
+            2 => MessageType::MethodReturn,
+            3 => MessageType::Error,
+            4 => MessageType::Signal,
+            t => return Err(DBusError::ProtoError(format!("unknown message type {}", t))),
+        };
+        let mut message = Message::new(message_type);
+        message.flags = data[2];
+        let mut reader = Reader { data: data, pos: 4, big_endian: big_endian };
+        let body_len = try!(reader.uint(4)) as usize;
+        message.serial = try!(reader.uint(4)) as u32;
+
+        let mut signature = String::new();
+        if let Value::Array(_, fields) = try!(reader.value(b"a(yv)")) {
+            for field in fields.into_iter() {
+                let (code, value) = match field {
+                    Value::Struct(mut parts) => match (parts.pop(), parts.pop()) {
+                        (Some(Value::Variant(value)), Some(Value::Byte(code))) => (code, *value),
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                let text = value.as_str().map(|s| s.to_owned());
+                match code {
+                    1 => message.path = text,
+                    2 => message.interface = text,
+                    3 => message.member = text,
+                    4 => message.error_name = text,
+                    5 => message.reply_serial = value.as_u32(),
+                    6 => message.destination = text,
+                    7 => message.sender = text,
+                    8 => signature = text.unwrap_or(String::new()),
+                    _ => {},
+                }
+            }
+        }
+        try!(reader.align(8));
+
+        let body = try!(reader.take(body_len));
+        let mut body_reader = Reader { data: body, pos: 0, big_endian: big_endian };
+        let mut rest = signature.as_bytes();
+        while !rest.is_empty() {
+            let len = try!(single_type_len(rest));
+            message.body.push(try!(body_reader.value(&rest[..len])));
+            rest = &rest[len..];
+        }
+        Ok(message)
+    }
+}
+
+pub struct DBusConnection {
+    stream: UnixStream,
+    next_serial: u32,
+    // Bytes received but not parsed yet
+    buffer: Vec<u8>,
+    // Messages received while waiting for a reply
+    queue: VecDeque<Message>,
+    pub unique_name: String,
+}
+
+impl DBusConnection {
+    // Connects to $DBUS_SESSION_BUS_ADDRESS, or to $XDG_RUNTIME_DIR/bus when it is not set
+    pub fn session() -> Result<DBusConnection, DBusError> {
+        let address = match env::var("DBUS_SESSION_BUS_ADDRESS") {
+            Ok(address) => try!(parse_address(&address)),
+            Err(_) => match env::var_os("XDG_RUNTIME_DIR") {
+                Some(dir) => BusAddress::Path(PathBuf::from(dir).join("bus")),
+                None => return Err(DBusError::ProtoError("no session bus address".to_owned())),
+            },
+        };
+        DBusConnection::open(&address)
+    }
+
+    fn open(address: &BusAddress) -> Result<DBusConnection, DBusError> {
+        let stream = match *address {
+            BusAddress::Path(ref path) => try!(UnixStream::connect(path)),
+            BusAddress::Abstract(ref name) => try!(connect_abstract(name)),
+        };
+        let mut connection = DBusConnection {
+            stream: stream,
+            next_serial: 1,
+            buffer: vec!(),
+            queue: VecDeque::new(),
+            unique_name: String::new(),
+        };
+        try!(connection.authenticate());
+        let hello = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello", vec!());
+        let reply = try!(connection.call(hello));
+        connection.unique_name = reply.body.first().and_then(|v| v.as_str()).unwrap_or("").to_owned();
+        Ok(connection)
+    }
+
+    // SASL EXTERNAL with the uid of the process, followed by BEGIN
+    fn authenticate(&mut self) -> Result<(), DBusError> {
+        let uid = unsafe { sys::getuid() }.to_string();
+        let hex_uid: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        try!(self.stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex_uid).as_bytes()));
+        let mut line = vec!();
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\r\n") {
+            if try!(self.stream.read(&mut byte)) == 0 {
+                return Err(DBusError::ProtoError("connection closed during authentication".to_owned()));
+            }
+            line.push(byte[0]);
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(DBusError::ProtoError(format!("authentication rejected: {}", String::from_utf8_lossy(&line).trim())));
+        }
+        try!(self.stream.write_all(b"BEGIN\r\n"));
+        Ok(())
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.stream.as_raw_fd()
+    }
+
+    // Returns the serial assigned to the message
+    pub fn send(&mut self, mut message: Message) -> Result<u32, DBusError> {
+        message.serial = self.next_serial;
+        self.next_serial += 1;
+        try!(self.stream.write_all(&message.marshal()));
+        Ok(message.serial)
+    }
+
+    // Sends a method call and waits for its reply; other messages are kept for poll_messages
+    pub fn call(&mut self, message: Message) -> Result<Message, DBusError> {
+        let serial = try!(self.send(message));
+        loop {
+            let message = match try!(self.take_message()) {
+                Some(message) => message,
+                None => {
+                    try!(self.fill());
+                    continue;
+                }
+            };
+            if message.reply_serial != Some(serial) {
+                self.queue.push_back(message);
+            } else if message.message_type == MessageType::Error {
+                let name = message.error_name.clone().unwrap_or(String::new());
+                let text = message.body.first().and_then(|v| v.as_str()).unwrap_or("").to_owned();
+                return Err(DBusError::MethodError(name, text));
+            } else {
+                return Ok(message);
+            }
+        }
+    }
+
+    // True if the connection became the primary owner of `name`
+    pub fn request_name(&mut self, name: &str) -> Result<bool, DBusError> {
+        // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        let flags = 0x4;
+        let request = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "RequestName", vec!(Value::Str(name.to_owned()), Value::UInt32(flags)));
+        let reply = try!(self.call(request));
+        // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+        Ok(reply.body.first().and_then(|v| v.as_u32()) == Some(1))
+    }
+
+    // Messages received so far. Never blocks; Err once the bus has closed the connection.
+    pub fn poll_messages(&mut self) -> Result<Vec<Message>, DBusError> {
+        try!(self.stream.set_nonblocking(true));
+        let filled = self.fill_available();
+        try!(self.stream.set_nonblocking(false));
+        try!(filled);
+        let mut messages: Vec<_> = self.queue.drain(..).collect();
+        while let Some(message) = try!(self.take_message()) {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn fill(&mut self) -> Result<usize, DBusError> {
+        let mut chunk = [0; 4096];
+        let len = try!(self.stream.read(&mut chunk));
+        if len == 0 {
+            return Err(DBusError::IOError(io::Error::new(io::ErrorKind::UnexpectedEof, "the bus closed the connection")));
+        }
+        self.buffer.extend(chunk[..len].iter().cloned());
+        Ok(len)
+    }
+
+    fn fill_available(&mut self) -> Result<(), DBusError> {
+        loop {
+            match self.fill() {
+                Ok(_) => {},
+                Err(DBusError::IOError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(DBusError::IOError(ref e)) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_message(&mut self) -> Result<Option<Message>, DBusError> {
+        if self.buffer.len() < 16 {
+            return Ok(None);
+        }
+        let len = try!(Message::wire_len(&self.buffer[..16]));
+        if self.buffer.len() < len {
+            return Ok(None);
+        }
+        let message = try!(Message::unmarshal(&self.buffer[..len]));
+        self.buffer.drain(..len);
+        Ok(Some(message))
+    }
+}
+
+#[derive(Debug,PartialEq)]
+enum BusAddress {
+    Path(PathBuf),
+    // Name in the Linux abstract socket namespace, without the leading NUL
+    Abstract(Vec<u8>),
+}
+
+// First `unix:path=` or `unix:abstract=` address of a semicolon-separated list
+fn parse_address(addresses: &str) -> Result<BusAddress, DBusError> {
+    for address in addresses.split(';') {
+        if !address.starts_with("unix:") {
+            continue;
+        }
+        for pair in address["unix:".len()..].split(',') {
+            if pair.starts_with("path=") {
+                return Ok(BusAddress::Path(PathBuf::from(OsString::from_vec(unescape(&pair["path=".len()..])))));
+            }
+            if pair.starts_with("abstract=") {
+                return Ok(BusAddress::Abstract(unescape(&pair["abstract=".len()..])));
+            }
+        }
+    }
+    Err(DBusError::ProtoError(format!("unsupported bus address `{}`", addresses)))
+}
+
+// UnixStream can only connect to sockets that have a path
+fn connect_abstract(name: &[u8]) -> io::Result<UnixStream> {
+    let mut addr = sys::sockaddr_un { sun_family: sys::AF_UNIX as c_ushort, sun_path: [0; 108] };
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "abstract socket name is too long"));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.iter()) {
+        *dst = *src as c_char;
+    }
+    // The address ends with the name, which is not NUL-terminated
+    let len = mem::size_of::<c_ushort>() + 1 + name.len();
+    unsafe {
+        let fd = sys::socket(sys::AF_UNIX, sys::SOCK_STREAM | sys::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sys::connect(fd, &addr, len as c_uint) < 0 {
+            let err = io::Error::last_os_error();
+            sys::close(fd);
+            return Err(err);
+        }
+        Ok(UnixStream::from_raw_fd(fd))
+    }
+}
+
+// Addresses escape bytes as %XX
+fn unescape(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut result = vec!();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                result.push(b);
+                i += 3;
+            },
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+    use std::process::Child;
+    use std::process::Command;
+    use std::process::Stdio;
+    use std::thread;
+    use std::time::Duration;
+
+    // One value of each type, with alignments that need padding in between
+    fn sample_body() -> Vec<Value> {
+        vec!(
+            Value::Byte(7),
+            Value::Int64(-3),
+            Value::Bool(true),
+            Value::Int16(-2),
+            Value::UInt16(2),
+            Value::Int32(-1),
+            Value::UInt32(1),
+            Value::UInt64(0xfedcba9876543210),
+            Value::Double(1.5),
+            Value::Str("héllo".to_owned()),
+            Value::ObjectPath("/a/b".to_owned()),
+            Value::Signature("a{sv}".to_owned()),
+            Value::Array("(xy)".to_owned(), vec!()),
+            Value::Array("(yd)".to_owned(), vec!(Value::Struct(vec!(Value::Byte(1), Value::Double(-0.25))))),
+            Value::variant_dict(vec!(("a", Value::Int32(1)), ("b", Value::string_array(&["x".to_owned(), "yz".to_owned()])))),
+            Value::Variant(Box::new(Value::Struct(vec!(Value::Str("s".to_owned()), Value::UInt64(9))))),
+        )
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let mut message = Message::method_call("org.example.Echo", "/org/example/Echo", "org.example.Echo", "Echo", sample_body());
+        message.serial = 42;
+        message.flags = NO_REPLY_EXPECTED;
+        let data = message.marshal();
+        assert_eq!(Message::wire_len(&data[..16]).unwrap(), data.len());
+
+        let decoded = Message::unmarshal(&data).unwrap();
+        assert_eq!(decoded.message_type, MessageType::MethodCall);
+        assert_eq!(decoded.serial, 42);
+        assert!(!decoded.expects_reply());
+        assert_eq!(decoded.destination, Some("org.example.Echo".to_owned()));
+        assert_eq!(decoded.path, Some("/org/example/Echo".to_owned()));
+        assert_eq!(decoded.interface, Some("org.example.Echo".to_owned()));
+        assert_eq!(decoded.member, Some("Echo".to_owned()));
+        assert_eq!(decoded.signature(), message.signature());
+        assert_eq!(decoded.body, sample_body());
+
+        let mut error = Message::error(&decoded, "org.example.Error", "failed");
+        error.serial = 43;
+        let decoded = Message::unmarshal(&error.marshal()).unwrap();
+        assert_eq!(decoded.message_type, MessageType::Error);
+        assert_eq!(decoded.error_name, Some("org.example.Error".to_owned()));
+        assert_eq!(decoded.reply_serial, Some(42));
+        assert_eq!(decoded.body, vec!(Value::Str("failed".to_owned())));
+    }
+
+    #[test]
+    fn reads_big_endian_messages() {
+        let data = [
+            b'B', 4, 0, 1, 0, 0, 0, 4, 0, 0, 0, 7, 0, 0, 0, 7,
+            // Signature field: code 8, variant of type `g` holding "u"
+            8, 1, b'g', 0, 1, b'u', 0, 0,
+            0, 0, 0, 42,
+        ];
+        let message = Message::unmarshal(&data).unwrap();
+        assert_eq!(message.message_type, MessageType::Signal);
+        assert_eq!(message.serial, 7);
+        assert_eq!(message.body, vec!(Value::UInt32(42)));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let data = Message::signal("/", "org.example.Echo", "Echo", sample_body()).marshal();
+        assert!(Message::unmarshal(&data[..data.len() - 1]).is_err());
+        let mut data = data;
+        data[0] = b'x';
+        assert!(Message::unmarshal(&data).is_err());
+        assert!(single_type_len(b"(ii").is_err());
+        assert!(single_type_len(b"h").is_err());
+        assert_eq!(single_type_len(b"a{sv}i").unwrap(), 5);
+    }
+
+    fn nested_arrays(depth: usize) -> Value {
+        (0..depth).fold(Value::Int32(1), |inner, _| Value::Array(inner.signature(), vec!(inner)))
+    }
+
+    fn nested_variants(depth: usize) -> Value {
+        (0..depth).fold(Value::Int32(1), |inner, _| Value::Variant(Box::new(inner)))
+    }
+
+    fn round_trip(value: Value) -> Result<Message, DBusError> {
+        Message::unmarshal(&Message::signal("/", "org.example.Echo", "Echo", vec!(value)).marshal())
+    }
+
+    #[test]
+    fn limits_nesting() {
+        assert_eq!(round_trip(nested_arrays(32)).unwrap().body, vec!(nested_arrays(32)));
+        assert!(round_trip(nested_arrays(33)).is_err());
+        let structs = |depth: usize| (0..depth).fold(Value::Byte(1), |inner, _| Value::Struct(vec!(inner)));
+        assert!(round_trip(structs(32)).is_ok());
+        assert!(round_trip(structs(33)).is_err());
+        assert!(round_trip(nested_variants(64)).is_ok());
+        assert!(round_trip(nested_variants(65)).is_err());
+        // Variants count towards the total, but not towards the arrays
+        let mixed = (0..40).fold(Value::Int32(1), |inner, _| Value::Variant(Box::new(Value::Array(inner.signature(), vec!(inner)))));
+        assert!(round_trip(mixed).is_err());
+        // A peer must not be able to overflow the stack: swap the body of a message carrying
+        // `v(i)` for far too many variants around the integer, keeping it aligned
+        let mut data = Message::signal("/", "org.example.Echo", "Echo", vec!(nested_variants(1))).marshal();
+        let body = data.split_off(data.len() - 8);
+        assert_eq!(body, vec!(1, b'i', 0, 0, 1, 0, 0, 0));
+        for _ in 0..100000 {
+            data.extend_from_slice(&[1, b'v', 0]);
+        }
+        data.extend_from_slice(&body);
+        let body_len = (data.len() - 16 - (data[12] as usize + 7) / 8 * 8) as u32;
+        data[4..8].copy_from_slice(&[body_len as u8, (body_len >> 8) as u8, (body_len >> 16) as u8, (body_len >> 24) as u8]);
+        match Message::unmarshal(&data) {
+            Err(DBusError::ProtoError(_)) => {},
+            _ => panic!("deep variants were accepted"),
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_address("unix:path=/run/user/1000/bus").unwrap(), BusAddress::Path(PathBuf::from("/run/user/1000/bus")));
+        assert_eq!(parse_address("tcp:host=localhost,port=1;unix:abstract=/tmp/dbus-a%2cb,guid=0123").unwrap(),
+                   BusAddress::Abstract(b"/tmp/dbus-a,b".to_vec()));
+        assert!(parse_address("tcp:host=localhost,port=1").is_err());
+        assert!(parse_address("unix:tmpdir=/tmp").is_err());
+    }
+
+    // dbus-daemon with a bus of its own, stopped when dropped
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        // None when dbus-daemon is not installed
+        fn start(listen: &str) -> Option<PrivateBus> {
+            let mut daemon = match Command::new("dbus-daemon")
+                .arg("--session").arg("--nofork").arg("--print-address").arg(format!("--address={}", listen))
+                .stdout(Stdio::piped()).stderr(Stdio::null()).spawn() {
+                Ok(daemon) => daemon,
+                Err(_) => return None,
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(PrivateBus { daemon: daemon, address: address.trim().to_owned() })
+        }
+
+        fn connect(&self) -> DBusConnection {
+            DBusConnection::open(&parse_address(&self.address).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    // Calls a method of another connection, which echoes the arguments back
+    fn echo_through(bus: &PrivateBus) {
+        let mut server = bus.connect();
+        assert!(server.unique_name.starts_with(':'));
+        assert!(server.request_name("org.example.Echo").unwrap());
+        let server = thread::spawn(move || {
+            for _ in 0..500 {
+                for message in server.poll_messages().unwrap().into_iter() {
+                    if message.member.as_ref().map(|m| &m[..]) == Some("Echo") {
+                        server.send(Message::method_return(&message, message.body.clone())).unwrap();
+                        return;
+                    }
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("no call received");
+        });
+
+        let mut client = bus.connect();
+        assert!(!client.request_name("org.example.Echo").unwrap());
+        let reply = client.call(Message::method_call("org.example.Echo", "/", "org.example.Echo", "Echo", sample_body())).unwrap();
+        assert_eq!(reply.body, sample_body());
+        server.join().unwrap();
+
+        match client.call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "NoSuchMethod", vec!())) {
+            Err(DBusError::MethodError(name, _)) => assert_eq!(name, "org.freedesktop.DBus.Error.UnknownMethod"),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn talks_to_bus_on_path_socket() {
+        if let Some(bus) = PrivateBus::start(&format!("unix:tmpdir={}", env::temp_dir().display())) {
+            echo_through(&bus);
+        }
+    }
+
+    #[test]
+    fn talks_to_bus_on_abstract_socket() {
+        let listen = format!("unix:abstract=/tmp/wacom-output-mapping-watcher-test-{}", unsafe { sys::getpid() });
+        if let Some(bus) = PrivateBus::start(&listen) {
+            assert!(bus.address.starts_with("unix:abstract="));
+            echo_through(&bus);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use dbus::*;
+use config::Target;
+use control::ControlCommand;
+use libc::c_int;
+use std::borrow::ToOwned;
+use std::mem;
+use xcb::XcbRandrOrientation;
+
+pub const BUS_NAME: &'static str = "org.wacom_output_mapping_watcher.Mapper";
+const OBJECT_PATH: &'static str = "/org/wacom_output_mapping_watcher/Mapper";
+const INTERFACE: &'static str = "org.wacom_output_mapping_watcher.Mapper";
+const PROPERTIES_INTERFACE: &'static str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE_INTERFACE: &'static str = "org.freedesktop.DBus.Introspectable";
+const PEER_INTERFACE: &'static str = "org.freedesktop.DBus.Peer";
+
+const INTROSPECTION: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.wacom_output_mapping_watcher.Mapper">
+    <property name="Target" type="s" access="read"/>
+    <property name="Outputs" type="as" access="read"/>
+    <property name="Devices" type="as" access="read"/>
+    <method name="SetTarget">
+      <arg name="target" type="s" direction="in"/>
+    </method>
+    <method name="Cycle">
+      <arg name="target" type="s" direction="out"/>
+    </method>
+    <method name="Reapply"/>
+    <signal name="MappingChanged">
+      <arg name="target" type="s"/>
+      <arg name="outputs" type="as"/>
+      <arg name="devices" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+// What the properties of the Mapper object report
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct MappingSnapshot {
+    // Target that applies to all devices, in the syntax accepted by SetTarget
+    pub target: String,
+    // Names of the active outputs
+    pub outputs: Vec<String>,
+    // Names of the devices whose matrix has been set
+    pub devices: Vec<String>,
+    // Where the active outputs are, so that moving or rotating one emits MappingChanged as well.
+    // Not a property of its own.
+    pub geometry: Vec<OutputGeometry>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct OutputGeometry {
+    pub name: String,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub orientation: XcbRandrOrientation,
+    pub primary: bool,
+}
+
+// Mapper object on the session bus. Property and introspection calls are answered here;
+// the other methods are handed over to the watcher as control commands.
+pub struct MapperService {
+    bus: DBusConnection,
+    snapshot: MappingSnapshot,
+}
+
+// Method call waiting for MapperService::reply
+pub struct MapperRequest {
+    call: Message,
+    pub command: ControlCommand,
+}
+
+impl MapperService {
+    pub fn start(snapshot: MappingSnapshot) -> Result<MapperService, DBusError> {
+        let mut bus = try!(DBusConnection::session());
+        if !try!(bus.request_name(BUS_NAME)) {
+            return Err(DBusError::ProtoError(format!("{} is already owned by another process", BUS_NAME)));
+        }
+        Ok(MapperService { bus: bus, snapshot: snapshot })
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.bus.fd()
+    }
+
+    // Method calls received so far. Never blocks.
+    pub fn poll_requests(&mut self) -> Result<Vec<MapperRequest>, DBusError> {
+        let mut requests = vec!();
+        for message in try!(self.bus.poll_messages()).into_iter() {
+            if message.message_type != MessageType::MethodCall {
+                continue;
+            }
+            match self.dispatch(&message) {
+                Ok(Some(command)) => requests.push(MapperRequest { call: message, command: command }),
+                Ok(None) => {},
+                Err((name, text)) => if message.expects_reply() {
+                    try!(self.bus.send(Message::error(&message, name, &text)));
+                },
+            }
+        }
+        Ok(requests)
+    }
+
+    // Answers the calls that do not need the watcher; returns the command for the others
+    fn dispatch(&mut self, call: &Message) -> Result<Option<ControlCommand>, (&'static str, String)> {
+        let member = call.member.clone().unwrap_or(String::new());
+        if call.path.as_ref().map(|p| &p[..]) != Some(OBJECT_PATH) {
+            return Err(("org.freedesktop.DBus.Error.UnknownObject", format!("No object at {}", call.path.clone().unwrap_or(String::new()))));
+        }
+        let string_arg = |idx: usize| call.body.get(idx).and_then(|v| match *v {
+            Value::Str(ref s) => Some(s.clone()),
+            _ => None,
+        });
+        let invalid_args = || ("org.freedesktop.DBus.Error.InvalidArgs", format!("Invalid arguments ({}) for {}", call.signature(), member));
+        let reply = match (call.interface.as_ref().map(|i| &i[..]).unwrap_or(""), &member[..]) {
+            (INTERFACE, "SetTarget") | ("", "SetTarget") => {
+                let spec = try!(string_arg(0).ok_or_else(&invalid_args));
+                let target = try!(Target::parse(&spec).map_err(|e| ("org.freedesktop.DBus.Error.InvalidArgs", e)));
+                return Ok(Some(ControlCommand::SetTarget(target)));
+            },
+            (INTERFACE, "Cycle") | ("", "Cycle") => return Ok(Some(ControlCommand::Cycle)),
+            (INTERFACE, "Reapply") | ("", "Reapply") => return Ok(Some(ControlCommand::Reapply)),
+            (PROPERTIES_INTERFACE, "Get") => {
+                let (interface, name) = match (string_arg(0), string_arg(1)) {
+                    (Some(interface), Some(name)) => (interface, name),
+                    _ => return Err(invalid_args()),
+                };
+                match self.properties().into_iter().find(|&(n, _)| interface == INTERFACE && n == name) {
+                    Some((_, value)) => vec!(Value::Variant(Box::new(value))),
+                    None => return Err(("org.freedesktop.DBus.Error.UnknownProperty", format!("No property {} in {}", name, interface))),
+                }
+            },
+            (PROPERTIES_INTERFACE, "GetAll") => match string_arg(0) {
+                Some(ref interface) if interface == INTERFACE => vec!(Value::variant_dict(self.properties())),
+                Some(_) => vec!(Value::variant_dict(vec!())),
+                None => return Err(invalid_args()),
+            },
+            (PROPERTIES_INTERFACE, "Set") => return Err(("org.freedesktop.DBus.Error.PropertyReadOnly", "All properties are read-only; use SetTarget".to_owned())),
+            (INTROSPECTABLE_INTERFACE, "Introspect") => vec!(Value::Str(INTROSPECTION.to_owned())),
+            (PEER_INTERFACE, "Ping") => vec!(),
+            (interface, _) => return Err(("org.freedesktop.DBus.Error.UnknownMethod", format!("No method {} in {}", member, interface))),
+        };
+        if call.expects_reply() {
+            if let Err(e) = self.bus.send(Message::method_return(call, reply)) {
+                warn!("Failed to reply on D-Bus: {}", e);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn reply(&mut self, request: MapperRequest, result: Result<String, String>) {
+        if !request.call.expects_reply() {
+            return;
+        }
+        let message = match result {
+            Ok(text) => match request.command {
+                ControlCommand::Cycle => Message::method_return(&request.call, vec!(Value::Str(text))),
+                _ => Message::method_return(&request.call, vec!()),
+            },
+            Err(text) => Message::error(&request.call, "org.freedesktop.DBus.Error.Failed", &text),
+        };
+        if let Err(e) = self.bus.send(message) {
+            warn!("Failed to reply on D-Bus: {}", e);
+        }
+    }
+
+    // Emits MappingChanged and PropertiesChanged when the snapshot differs from the last one
+    pub fn publish(&mut self, snapshot: MappingSnapshot) -> Result<(), DBusError> {
+        if snapshot == self.snapshot {
+            return Ok(());
+        }
+        let previous = mem::replace(&mut self.snapshot, snapshot);
+        let changed: Vec<_> = self.properties().into_iter().filter(|&(name, _)| match name {
+            "Target" => previous.target != self.snapshot.target,
+            "Outputs" => previous.outputs != self.snapshot.outputs,
+            _ => previous.devices != self.snapshot.devices,
+        }).collect();
+        try!(self.bus.send(Message::signal(OBJECT_PATH, INTERFACE, "MappingChanged", vec!(
+            Value::Str(self.snapshot.target.clone()),
+            Value::string_array(&self.snapshot.outputs),
+            Value::string_array(&self.snapshot.devices),
+        ))));
+        if changed.is_empty() {
+            return Ok(());
+        }
+        try!(self.bus.send(Message::signal(OBJECT_PATH, PROPERTIES_INTERFACE, "PropertiesChanged", vec!(
+            Value::Str(INTERFACE.to_owned()),
+            Value::variant_dict(changed),
+            Value::string_array(&[]),
+        ))));
+        Ok(())
+    }
+
+    fn properties(&self) -> Vec<(&'static str, Value)> {
+        vec!(
+            ("Target", Value::Str(self.snapshot.target.clone())),
+            ("Outputs", Value::string_array(&self.snapshot.outputs)),
+            ("Devices", Value::string_array(&self.snapshot.devices)),
+        )
+    }
+}
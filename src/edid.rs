@@ -0,0 +1,235 @@
+use std::fmt;
+use std::result::Result;
+use std::str;
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const DESCRIPTOR_OFFSETS: [usize; 4] = [54, 72, 90, 108];
+const DESCRIPTOR_SERIAL: u8 = 0xff;
+const DESCRIPTOR_MONITOR_NAME: u8 = 0xfc;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct EdidIdentity {
+    pub manufacturer: String,
+    pub product_code: u16,
+    pub serial_number: u32,
+    pub monitor_name: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl EdidIdentity {
+    pub fn parse(data: &[u8]) -> Option<EdidIdentity> {
+        if data.len() < 128 || data[..8] != EDID_HEADER[..] {
+            return None;
+        }
+        // The bytes of the base block add up to 0 (mod 256)
+        if data[..128].iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return None;
+        }
+
+        let packed = (data[8] as u16) << 8 | data[9] as u16;
+        let manufacturer: String = [10, 5, 0].iter()
+            .map(|shift| (((packed >> *shift) & 0x1f) as u8 + b'@') as char)
+            .collect();
+        let product_code = data[10] as u16 | (data[11] as u16) << 8;
+        let serial_number = data[12] as u32 | (data[13] as u32) << 8 | (data[14] as u32) << 16 | (data[15] as u32) << 24;
+
+        let mut monitor_name = None;
+        let mut serial = None;
+        for offset in DESCRIPTOR_OFFSETS.iter() {
+            let descriptor = &data[*offset..*offset + 18];
+            // Display descriptors start with a zero pixel clock
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                continue;
+            }
+            match descriptor[3] {
+                DESCRIPTOR_MONITOR_NAME => monitor_name = descriptor_text(descriptor),
+                DESCRIPTOR_SERIAL => serial = descriptor_text(descriptor),
+                _ => {}
+            }
+        }
+
+        Some(EdidIdentity {
+            manufacturer: manufacturer,
+            product_code: product_code,
+            serial_number: serial_number,
+            monitor_name: monitor_name,
+            serial: serial,
+        })
+    }
+
+    // Serial number string, or the numeric serial number when there is none or when it could
+    // not be part of a selector
+    pub fn serial(&self) -> String {
+        match self.serial {
+            Some(ref serial) if !serial.contains(':') && !serial.contains(char::is_whitespace) => serial.clone(),
+            _ => format!("{}", self.serial_number),
+        }
+    }
+}
+
+// In the syntax of EdidSelector, which can be pasted unquoted: the monitor name may contain
+// spaces and colons, so the product code stands for the model
+impl fmt::Display for EdidIdentity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}:{:04x}:{}", self.manufacturer, self.product_code, self.serial())
+    }
+}
+
+fn descriptor_text(descriptor: &[u8]) -> Option<String> {
+    let text = &descriptor[5..18];
+    let end = text.iter().position(|c| *c == b'\n').unwrap_or(text.len());
+    match str::from_utf8(&text[..end]) {
+        Ok(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        _ => None,
+    }
+}
+
+// Matches `edid:MANUFACTURER[:MODEL[:SERIAL]]`; empty components match anything. MODEL is
+// compared with the monitor name (or any word of it) and the hexadecimal product code,
+// SERIAL with the serial number string and the numeric serial number.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EdidSelector {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl EdidSelector {
+    pub fn parse(spec: &str) -> Result<EdidSelector, String> {
+        let parts: Vec<_> = spec.split(':').collect();
+        if parts.len() > 3 {
+            return Err(format!("Invalid EDID selector `edid:{}`: expected edid:MANUFACTURER[:MODEL[:SERIAL]]", spec));
+        }
+        let part = |idx: usize| parts.get(idx).and_then(|p| if p.is_empty() { None } else { Some(p.to_string()) });
+        Ok(EdidSelector {
+            manufacturer: part(0),
+            model: part(1),
+            serial: part(2),
+        })
+    }
+
+    pub fn matches(&self, identity: &EdidIdentity) -> bool {
+        let manufacturer_matches = match self.manufacturer {
+            Some(ref m) => m.to_uppercase() == identity.manufacturer,
+            None => true,
+        };
+        let model_matches = match self.model {
+            Some(ref m) => {
+                let name_matches = match identity.monitor_name {
+                    Some(ref name) => name == m || name.split_whitespace().any(|word| word == m),
+                    None => false,
+                };
+                name_matches || m.to_lowercase() == format!("{:04x}", identity.product_code)
+            },
+            None => true,
+        };
+        let serial_matches = match self.serial {
+            Some(ref s) => identity.serial.as_ref() == Some(s) || *s == format!("{}", identity.serial_number),
+            None => true,
+        };
+        manufacturer_matches && model_matches && serial_matches
+    }
+}
+
+impl fmt::Display for EdidSelector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let part = |p: &Option<String>| p.clone().unwrap_or(String::new());
+        write!(fmt, "edid:{}:{}:{}", part(&self.manufacturer), part(&self.model), part(&self.serial))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base EDID block of a Dell U2720Q with the given display descriptors
+    fn edid(descriptors: &[(u8, &str)]) -> Vec<u8> {
+        let mut data = vec!(0u8; 128);
+        data[..8].copy_from_slice(&EDID_HEADER);
+        // "DEL": D=4, E=5, L=12
+        let packed: u16 = 4 << 10 | 5 << 5 | 12;
+        data[8] = (packed >> 8) as u8;
+        data[9] = packed as u8;
+        data[10] = 0xb9;
+        data[11] = 0xa0;
+        data[12..16].copy_from_slice(&[0x4c, 0x31, 0x32, 0x33]);
+        // A detailed timing descriptor, which is skipped
+        data[54] = 0x56;
+        data[55] = 0x5e;
+        for (&(tag, text), offset) in descriptors.iter().zip(DESCRIPTOR_OFFSETS[1..].iter()) {
+            data[offset + 3] = tag;
+            let mut bytes = text.as_bytes().to_vec();
+            bytes.push(b'\n');
+            bytes.resize(13, b' ');
+            data[offset + 5..offset + 18].copy_from_slice(&bytes[..13]);
+        }
+        let sum = data[..127].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        data[127] = 0u8.wrapping_sub(sum);
+        data
+    }
+
+    #[test]
+    fn parses_identity_and_descriptors() {
+        let identity = EdidIdentity::parse(&edid(&[(0xfc, "DELL U2720Q"), (0xff, "ABC123")])).unwrap();
+        assert_eq!(identity.manufacturer, "DEL");
+        assert_eq!(identity.product_code, 0xa0b9);
+        assert_eq!(identity.serial_number, 0x3332314c);
+        assert_eq!(identity.monitor_name, Some("DELL U2720Q".to_string()));
+        assert_eq!(identity.serial, Some("ABC123".to_string()));
+        assert_eq!(identity.to_string(), "DEL:a0b9:ABC123");
+    }
+
+    #[test]
+    fn falls_back_to_numeric_model_and_serial() {
+        let identity = EdidIdentity::parse(&edid(&[(0xfe, "unspecified")])).unwrap();
+        assert_eq!(identity.monitor_name, None);
+        assert_eq!(identity.serial, None);
+        assert_eq!(identity.to_string(), format!("DEL:a0b9:{}", 0x3332314c));
+    }
+
+    #[test]
+    fn rejects_bad_checksum_header_and_length() {
+        let mut data = edid(&[(0xfc, "DELL U2720Q")]);
+        assert!(EdidIdentity::parse(&data).is_some());
+        // Extension blocks are not part of the checksum
+        data.extend(vec!(0x02; 128));
+        assert!(EdidIdentity::parse(&data).is_some());
+        data[20] ^= 1;
+        assert!(EdidIdentity::parse(&data).is_none());
+        data[20] ^= 1;
+        data[0] = 0xff;
+        assert!(EdidIdentity::parse(&data).is_none());
+        assert!(EdidIdentity::parse(&edid(&[])[..127]).is_none());
+    }
+
+    #[test]
+    fn selector_matches_name_word_product_code_and_serials() {
+        let identity = EdidIdentity::parse(&edid(&[(0xfc, "DELL U2720Q"), (0xff, "ABC123")])).unwrap();
+        let matches = |spec: &str| EdidSelector::parse(spec).unwrap().matches(&identity);
+        assert!(matches("DEL"));
+        assert!(matches("del:U2720Q:ABC123"));
+        assert!(matches("DEL:DELL U2720Q"));
+        assert!(matches("DEL:A0B9"));
+        assert!(matches("::ABC123"));
+        assert!(matches(&format!("DEL::{}", 0x3332314c)));
+        assert!(!matches("GSM"));
+        assert!(!matches("DEL:U2720"));
+        assert!(!matches("DEL:U2720Q:XYZ"));
+        assert!(EdidSelector::parse("DEL:U2720Q:ABC123:1").is_err());
+    }
+
+    #[test]
+    fn display_round_trips_through_selector() {
+        let identities = [
+            EdidIdentity::parse(&edid(&[(0xfc, "DELL U2720Q"), (0xff, "ABC123")])).unwrap(),
+            EdidIdentity::parse(&edid(&[(0xfc, "A:B C"), (0xff, "SN 1:2")])).unwrap(),
+            EdidIdentity::parse(&edid(&[])).unwrap(),
+        ];
+        for identity in identities.iter() {
+            let spec = identity.to_string();
+            assert!(!spec.contains(char::is_whitespace));
+            assert!(EdidSelector::parse(&spec).unwrap().matches(identity), "{} does not match itself", spec);
+        }
+        assert_eq!(identities[1].to_string(), format!("DEL:a0b9:{}", 0x3332314c));
+    }
+}
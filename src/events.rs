@@ -0,0 +1,252 @@
+use xcb::*;
+use config::Target;
+use outputs::XcbOutputDescription;
+use list::connection_name;
+use list::reflection_name;
+use list::rotation_name;
+use rustc_serialize::json::Json;
+use rustc_serialize::json::ToJson;
+use std::borrow::ToOwned;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use sys;
+
+// Stream of JSON objects, one per line, describing what the watcher sees and does. Every
+// object has an `event` name and a `time` in seconds since the Unix epoch.
+pub struct EventLog {
+    // None while a FIFO has no reader; events are dropped then
+    out: Option<Box<Write>>,
+    // File or FIFO to open again when there is no reader, None for stdout
+    path: Option<PathBuf>,
+}
+
+impl EventLog {
+    // PATH `-` is stdout; regular messages are then moved to stderr, so that stdout only
+    // carries events. Opening a FIFO does not wait for a reader.
+    pub fn open(path: &str) -> io::Result<EventLog> {
+        if path == "-" {
+            let fd = unsafe { sys::dup(sys::STDOUT_FILENO) };
+            if fd < 0 || unsafe { sys::dup2(sys::STDERR_FILENO, sys::STDOUT_FILENO) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(EventLog { out: Some(Box::new(unsafe { File::from_raw_fd(fd) })), path: None });
+        }
+        let out = try!(open_without_blocking(Path::new(path)));
+        if out.is_none() {
+            info!("{} has no reader yet, events are dropped until one connects", path);
+        }
+        Ok(EventLog { out: out.map(|file| Box::new(file) as Box<Write>), path: Some(PathBuf::from(path)) })
+    }
+
+    pub fn randr_change(&mut self, event: &XcbRandrEvent) {
+        let mut object = BTreeMap::new();
+        match *event {
+            XcbRandrEvent::ScreenChange { width, height, rotation } => {
+                let orientation = XcbRandrOrientation::from_u16(rotation);
+                object.insert("type".to_owned(), "screen".to_json());
+                object.insert("width".to_owned(), (width as u64).to_json());
+                object.insert("height".to_owned(), (height as u64).to_json());
+                object.insert("rotation".to_owned(), rotation_name(orientation.rotation).to_json());
+                object.insert("reflection".to_owned(), reflection_name(&orientation).to_json());
+            },
+            XcbRandrEvent::CrtcChange { crtc, x, y, width, height, rotation } => {
+                let orientation = XcbRandrOrientation::from_u16(rotation);
+                object.insert("type".to_owned(), "crtc".to_json());
+                object.insert("crtc".to_owned(), crtc.to_json());
+                object.insert("x".to_owned(), (x as i64).to_json());
+                object.insert("y".to_owned(), (y as i64).to_json());
+                object.insert("width".to_owned(), (width as u64).to_json());
+                object.insert("height".to_owned(), (height as u64).to_json());
+                object.insert("rotation".to_owned(), rotation_name(orientation.rotation).to_json());
+                object.insert("reflection".to_owned(), reflection_name(&orientation).to_json());
+            },
+            XcbRandrEvent::OutputChange { output, crtc, ref connection } => {
+                object.insert("type".to_owned(), "output".to_json());
+                object.insert("output".to_owned(), output.to_json());
+                object.insert("crtc".to_owned(), crtc.to_json());
+                object.insert("connection".to_owned(), connection_name(connection).to_json());
+            },
+            XcbRandrEvent::OutputProperty { output, ref property } => {
+                object.insert("type".to_owned(), "output-property".to_json());
+                object.insert("output".to_owned(), output.to_json());
+                object.insert("property".to_owned(), property.to_json());
+            },
+            XcbRandrEvent::Other(ref event_type) => {
+                let name = match *event_type {
+                    XcbRandrEventType::ProviderChange => "provider",
+                    XcbRandrEventType::ProviderProperty => "provider-property",
+                    XcbRandrEventType::ResourceChange => "resource",
+                    _ => "other",
+                };
+                object.insert("type".to_owned(), name.to_json());
+            },
+        }
+        self.emit("randr-change", object);
+    }
+
+    // Devices are given as (id, name)
+    pub fn hierarchy_change(&mut self, added: &[(u16, String)], removed: &[(u16, String)]) {
+        let devices = |list: &[(u16, String)]| list.iter().map(|&(id, ref name)| {
+            let mut device = BTreeMap::new();
+            device.insert("id".to_owned(), (id as u64).to_json());
+            device.insert("name".to_owned(), name.to_json());
+            Json::Object(device)
+        }).collect::<Vec<_>>().to_json();
+        let mut object = BTreeMap::new();
+        object.insert("added".to_owned(), devices(added));
+        object.insert("removed".to_owned(), devices(removed));
+        self.emit("hierarchy-change", object);
+    }
+
+    pub fn layout_change(&mut self, previous: &[XcbOutputDescription], current: &[XcbOutputDescription]) {
+        let find = |outputs: &[XcbOutputDescription], name: &str| outputs.iter().any(|o| o.name == name);
+        let added: Vec<_> = current.iter().filter(|o| !find(previous, &o.name)).map(output_json).collect();
+        let removed: Vec<_> = previous.iter().filter(|o| !find(current, &o.name)).map(output_json).collect();
+        let changed: Vec<_> = current.iter()
+            .filter(|o| previous.iter().any(|p| p.name == o.name && p != *o))
+            .map(output_json)
+            .collect();
+        let mut object = BTreeMap::new();
+        object.insert("added".to_owned(), added.to_json());
+        object.insert("removed".to_owned(), removed.to_json());
+        object.insert("changed".to_owned(), changed.to_json());
+        object.insert("outputs".to_owned(), current.iter().map(output_json).collect::<Vec<_>>().to_json());
+        self.emit("layout-change", object);
+    }
+
+    pub fn matrix_applied(&mut self, device: &XcbInputDevice, target: &Target, matrix: &[f32]) {
+        let mut object = BTreeMap::new();
+        object.insert("device".to_owned(), (device.deviceid as u64).to_json());
+        object.insert("name".to_owned(), device.name.to_json());
+        object.insert("target".to_owned(), target.to_string().to_json());
+        object.insert("matrix".to_owned(), matrix.iter().map(|v| *v as f64).collect::<Vec<_>>().to_json());
+        self.emit("matrix-applied", object);
+    }
+
+    fn emit(&mut self, event: &str, mut object: BTreeMap<String, Json>) {
+        object.insert("event".to_owned(), event.to_json());
+        object.insert("time".to_owned(), timestamp().to_json());
+        let line = format!("{}\n", Json::Object(object));
+        if self.out.is_none() {
+            if let Some(ref path) = self.path {
+                match open_without_blocking(path) {
+                    Ok(out) => self.out = out.map(|file| Box::new(file) as Box<Write>),
+                    Err(e) => warn!("Failed to open {}: {}", path.display(), e),
+                }
+            }
+        }
+        let result = match self.out {
+            Some(ref mut out) => out.write_all(line.as_bytes()).and_then(|_| out.flush()),
+            None => return,
+        };
+        match result {
+            Ok(_) => {},
+            // The reader of the FIFO has gone away; wait for the next one
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe && self.path.is_some() => {
+                debug!("The reader of the event stream has gone away");
+                self.out = None;
+            },
+            Err(e) => warn!("Failed to write an event: {}", e),
+        }
+    }
+}
+
+fn output_json(output: &XcbOutputDescription) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("name".to_owned(), output.name.to_json());
+    object.insert("x".to_owned(), (output.x as i64).to_json());
+    object.insert("y".to_owned(), (output.y as i64).to_json());
+    object.insert("width".to_owned(), (output.width as u64).to_json());
+    object.insert("height".to_owned(), (output.height as u64).to_json());
+    object.insert("rotation".to_owned(), rotation_name(output.orientation.rotation).to_json());
+    object.insert("reflection".to_owned(), reflection_name(&output.orientation).to_json());
+    object.insert("primary".to_owned(), output.primary.to_json());
+    object.insert("edid".to_owned(), output.edid.as_ref().map(|e| e.to_string()).to_json());
+    Json::Object(object)
+}
+
+// Appends to `path`; None for a FIFO without a reader (ENXIO), which a blocking open would wait
+// for. The file is switched back to blocking writes, so that no event is cut short.
+fn open_without_blocking(path: &Path) -> io::Result<Option<File>> {
+    let file = match OpenOptions::new().create(true).append(true).custom_flags(sys::O_NONBLOCK).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.raw_os_error() == Some(sys::ENXIO) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let fd = file.as_raw_fd();
+    let flags = unsafe { sys::fcntl(fd, sys::F_GETFL) };
+    if flags < 0 || unsafe { sys::fcntl(fd, sys::F_SETFL, flags & !sys::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(file))
+}
+
+fn timestamp() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ffi::CString;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn reader(path: &Path) -> File {
+        OpenOptions::new().read(true).custom_flags(sys::O_NONBLOCK).open(path).unwrap()
+    }
+
+    fn read_available(file: &mut File) -> String {
+        let mut buffer = [0u8; 4096];
+        match file.read(&mut buffer) {
+            Ok(len) => String::from_utf8_lossy(&buffer[..len]).into_owned(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => String::new(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn fifo_without_reader_does_not_block() {
+        let path = env::temp_dir().join(format!("wacom-output-mapping-watcher-events-{}", unsafe { sys::getpid() }));
+        let _ = fs::remove_file(&path);
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { sys::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let mut events = EventLog::open(path.to_str().unwrap()).unwrap();
+        // Dropped, as nobody listens
+        events.layout_change(&[], &[]);
+
+        let mut first = reader(&path);
+        events.hierarchy_change(&[(10, "Wacom Pen stylus".to_owned())], &[]);
+        let line = read_available(&mut first);
+        assert!(line.starts_with("{") && line.ends_with("}\n"), "unexpected event {:?}", line);
+        assert!(line.contains("\"event\":\"hierarchy-change\""));
+        assert!(line.contains("Wacom Pen stylus"));
+
+        // The reader goes away and another one connects
+        drop(first);
+        events.layout_change(&[], &[]);
+        let mut second = reader(&path);
+        events.layout_change(&[], &[]);
+        let line = read_available(&mut second);
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.contains("\"event\":\"layout-change\""));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
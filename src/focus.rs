@@ -0,0 +1,105 @@
+use xcb::*;
+use ffi::*;
+use mapping::Rect;
+
+// Tracks the window named by _NET_ACTIVE_WINDOW on the root window and its rectangle on
+// the screen. Reparenting window managers move the frame rather than the client window,
+// so ConfigureNotify is watched on both the window and its frame (the ancestor that is a
+// child of the root window).
+pub struct FocusTracker {
+    root: xcb_window_t,
+    active_window_atom: xcb_atom_t,
+    window: xcb_window_t,
+    frame: xcb_window_t,
+    rect: Option<Rect>,
+}
+
+impl FocusTracker {
+    pub fn new(connection: &XcbConnection, root: xcb_window_t) -> Result<FocusTracker, XcbError> {
+        let active_window_atom = try!(connection.intern_atom("_NET_ACTIVE_WINDOW", false));
+        try!(connection.select_window_events(root, XCB_EVENT_MASK_PROPERTY_CHANGE));
+        Ok(FocusTracker {
+            root: root,
+            active_window_atom: active_window_atom,
+            window: XCB_WINDOW_NONE,
+            frame: XCB_WINDOW_NONE,
+            rect: None,
+        })
+    }
+
+    // Rectangle of the active window, None when no window is active
+    pub fn rect(&self) -> Option<Rect> {
+        self.rect
+    }
+
+    // Starts tracking the window currently named by _NET_ACTIVE_WINDOW. Returns true when
+    // the rectangle has changed.
+    pub fn update_window(&mut self, connection: &XcbConnection) -> bool {
+        let window = match connection.get_window_property_u32(self.root, self.active_window_atom, XCB_ATOM_WINDOW) {
+            Ok(ref values) if !values.is_empty() => values[0],
+            _ => XCB_WINDOW_NONE,
+        };
+        if window != self.window {
+            self.watch(connection, XCB_EVENT_MASK_NO_EVENT);
+            self.window = window;
+            self.frame = find_frame(connection, self.root, window);
+            self.watch(connection, XCB_EVENT_MASK_STRUCTURE_NOTIFY);
+        }
+        self.update_rect(connection)
+    }
+
+    // Returns true when the event has changed the rectangle of the active window
+    pub fn handle_event(&mut self, connection: &XcbConnection, event: &xcb_generic_event_t) -> bool {
+        match event.response_type & 0x7f {
+            28 /* XCB_PROPERTY_NOTIFY */ => {
+                let notify = unsafe { &*(event as *const _ as *const xcb_property_notify_event_t) };
+                notify.window == self.root && notify.atom == self.active_window_atom && self.update_window(connection)
+            },
+            22 /* XCB_CONFIGURE_NOTIFY */ => {
+                let notify = unsafe { &*(event as *const _ as *const xcb_configure_notify_event_t) };
+                (notify.window == self.window || notify.window == self.frame) && self.update_rect(connection)
+            },
+            _ => false,
+        }
+    }
+
+    fn watch(&self, connection: &XcbConnection, event_mask: u32) {
+        for window in [self.window, self.frame].iter() {
+            // The window may already be destroyed, in which case there is nothing to watch
+            if *window != XCB_WINDOW_NONE && *window != self.root {
+                let _ = connection.select_window_events(*window, event_mask);
+            }
+        }
+    }
+
+    fn update_rect(&mut self, connection: &XcbConnection) -> bool {
+        let rect = if self.window == XCB_WINDOW_NONE {
+            None
+        } else {
+            connection.get_window_rect(self.window, self.root).ok().map(|(x, y, width, height)| Rect {
+                x: x as f32,
+                y: y as f32,
+                width: width as f32,
+                height: height as f32,
+            })
+        };
+        if rect == self.rect {
+            false
+        } else {
+            self.rect = rect;
+            true
+        }
+    }
+}
+
+fn find_frame(connection: &XcbConnection, root: xcb_window_t, window: xcb_window_t) -> xcb_window_t {
+    let mut frame = window;
+    while frame != XCB_WINDOW_NONE {
+        match connection.get_window_parent(frame) {
+            Ok(parent) if parent == root || parent == XCB_WINDOW_NONE => return frame,
+            Ok(parent) => frame = parent,
+            Err(_) => return XCB_WINDOW_NONE,
+        }
+    }
+    XCB_WINDOW_NONE
+}
@@ -0,0 +1,147 @@
+use outputs::XcbOutputDescription;
+use std::time::Duration;
+use std::time::Instant;
+
+// Tracks which output contains the pointer. A switch to another output happens only when
+// the pointer has stayed at least `hysteresis` pixels inside of it for `dwell`, so that
+// moving along a monitor edge does not make the mapping flap.
+pub struct PointerFollower {
+    dwell: Duration,
+    hysteresis: i32,
+    current: Option<String>,
+    candidate: Option<(String, Instant)>,
+}
+
+impl PointerFollower {
+    pub fn new(dwell_ms: u64, hysteresis: i32) -> PointerFollower {
+        PointerFollower {
+            dwell: Duration::from_millis(dwell_ms),
+            hysteresis: hysteresis,
+            current: None,
+            candidate: None,
+        }
+    }
+
+    // Returns the name of the output the tablet should be switched to, if any. `now` is when
+    // the pointer was at `x`, `y`.
+    pub fn update(&mut self, outputs: &[XcbOutputDescription], x: i16, y: i16, now: Instant) -> Option<String> {
+        let under = match outputs.iter().filter(|o| contains(o, x, y, 0)).nth(0) {
+            Some(output) => output,
+            None => return None,
+        };
+        if self.current.as_ref() == Some(&under.name) {
+            self.candidate = None;
+            return None;
+        }
+        if self.current.is_none() {
+            self.current = Some(under.name.clone());
+            return self.current.clone();
+        }
+        if !contains(under, x, y, self.hysteresis) {
+            // The pointer has to dwell again once it is past the margin
+            self.candidate = None;
+            return None;
+        }
+        let dwelt = match self.candidate {
+            Some((ref name, since)) if *name == under.name => now.duration_since(since) >= self.dwell,
+            _ => {
+                self.candidate = Some((under.name.clone(), now));
+                self.dwell == Duration::from_millis(0)
+            }
+        };
+        if dwelt {
+            self.candidate = None;
+            self.current = Some(under.name.clone());
+            self.current.clone()
+        } else {
+            None
+        }
+    }
+}
+
+fn contains(output: &XcbOutputDescription, x: i16, y: i16, margin: i32) -> bool {
+    let (x, y) = (x as i32, y as i32);
+    let (left, top) = (output.x as i32, output.y as i32);
+    let (right, bottom) = (left + output.width as i32, top + output.height as i32);
+    x >= left + margin && x < right - margin && y >= top + margin && y < bottom - margin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use outputs::XcbOutputDescription;
+    use std::borrow::ToOwned;
+    use xcb::XcbRandrOrientation;
+
+    fn output(name: &str, x: i16) -> XcbOutputDescription {
+        XcbOutputDescription {
+            name: name.to_owned(),
+            x: x,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            orientation: XcbRandrOrientation::from_u16(1),
+            edid: None,
+            primary: false,
+            mm_width: 0,
+            mm_height: 0,
+        }
+    }
+
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn initial_position_switches_at_once() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        let mut follower = PointerFollower::new(300, 20);
+        let start = Instant::now();
+        assert_eq!(follower.update(&outputs, -5, 10, start), None);
+        assert_eq!(follower.update(&outputs, 1925, 10, start), Some("DP-1".to_owned()));
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 1000)), None);
+    }
+
+    #[test]
+    fn switches_once_the_pointer_has_dwelt() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        let mut follower = PointerFollower::new(300, 20);
+        let start = Instant::now();
+        follower.update(&outputs, 100, 100, start);
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 10)), None);
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 309)), None);
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 310)), Some("DP-1".to_owned()));
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 320)), None);
+        // Going back restarts the dwell
+        assert_eq!(follower.update(&outputs, 100, 100, at(start, 400)), None);
+        assert_eq!(follower.update(&outputs, 2500, 500, at(start, 500)), None);
+        assert_eq!(follower.update(&outputs, 100, 100, at(start, 600)), None);
+        assert_eq!(follower.update(&outputs, 100, 100, at(start, 900)), Some("eDP-1".to_owned()));
+    }
+
+    #[test]
+    fn zero_dwell_switches_at_once() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        let mut follower = PointerFollower::new(0, 20);
+        let start = Instant::now();
+        follower.update(&outputs, 100, 100, start);
+        assert_eq!(follower.update(&outputs, 2500, 500, start), Some("DP-1".to_owned()));
+    }
+
+    #[test]
+    fn margin_does_not_count_towards_the_dwell() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        let mut follower = PointerFollower::new(300, 20);
+        let start = Instant::now();
+        follower.update(&outputs, 100, 100, start);
+        // Within 20 pixels of the edge of DP-1
+        assert_eq!(follower.update(&outputs, 1930, 500, at(start, 10)), None);
+        assert_eq!(follower.update(&outputs, 1930, 500, at(start, 1000)), None);
+        // Hovering inside, then at the edge again: the dwell starts over when crossing
+        assert_eq!(follower.update(&outputs, 2000, 500, at(start, 1100)), None);
+        assert_eq!(follower.update(&outputs, 1930, 500, at(start, 1300)), None);
+        assert_eq!(follower.update(&outputs, 2000, 500, at(start, 1450)), None);
+        assert_eq!(follower.update(&outputs, 2000, 500, at(start, 1749)), None);
+        assert_eq!(follower.update(&outputs, 2000, 500, at(start, 1750)), Some("DP-1".to_owned()));
+    }
+}
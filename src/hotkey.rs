@@ -0,0 +1,153 @@
+use xcb::*;
+use ffi::*;
+use std::result::Result;
+use std::borrow::ToOwned;
+
+// Modifiers that are ignored when matching a key press
+const IGNORED_MODIFIERS: [u16; 4] = [
+    0,
+    XCB_MOD_MASK_LOCK as u16,
+    XCB_MOD_MASK_2 as u16, // Num Lock
+    XCB_MOD_MASK_LOCK as u16 | XCB_MOD_MASK_2 as u16,
+];
+
+// Key combination like Super+Shift+T
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Hotkey {
+    pub modifiers: u16,
+    pub keysym: xcb_keysym_t,
+    pub spec: String,
+}
+
+impl Hotkey {
+    pub fn parse(spec: &str) -> Result<Hotkey, String> {
+        let parts: Vec<_> = spec.split('+').map(|p| p.trim()).collect();
+        let (key, modifier_names) = parts.split_last().unwrap();
+        let mut modifiers = 0;
+        for name in modifier_names.iter() {
+            modifiers |= match &name.to_lowercase()[..] {
+                "shift" => XCB_MOD_MASK_SHIFT,
+                "ctrl" | "control" => XCB_MOD_MASK_CONTROL,
+                "alt" | "mod1" => XCB_MOD_MASK_1,
+                "super" | "win" | "mod4" => XCB_MOD_MASK_4,
+                _ => return Err(format!("Invalid hotkey `{}`: unknown modifier `{}`", spec, name)),
+            } as u16;
+        }
+        let keysym = match parse_keysym(key) {
+            Some(keysym) => keysym,
+            None => return Err(format!("Invalid hotkey `{}`: unknown key `{}`", spec, key)),
+        };
+        Ok(Hotkey { modifiers: modifiers, keysym: keysym, spec: spec.to_owned() })
+    }
+
+    pub fn grab(&self, connection: &XcbConnection, root_window_id: xcb_window_t) -> Result<HotkeyGrab, XcbError> {
+        let setup = connection.get_setup();
+        let (min_keycode, max_keycode) = (setup.min_keycode(), setup.max_keycode());
+        let mapping = try!(connection.get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1));
+        let keycodes = mapping.keycodes_for(self.keysym);
+        if keycodes.is_empty() {
+            return Err(XcbError::LogicError(format!("No key produces `{}`", self.spec)));
+        }
+        let mut grabbed = vec!();
+        for keycode in keycodes.iter() {
+            for ignored in IGNORED_MODIFIERS.iter() {
+                let modifiers = self.modifiers | *ignored;
+                if let Err(e) = connection.grab_key(root_window_id, modifiers, *keycode) {
+                    // Do not leave a partial grab behind, e.g. when another client holds one combination
+                    for &(modifiers, keycode) in grabbed.iter() {
+                        let _ = connection.ungrab_key(root_window_id, modifiers, keycode);
+                    }
+                    return Err(e);
+                }
+                grabbed.push((modifiers, *keycode));
+            }
+        }
+        Ok(HotkeyGrab { modifiers: self.modifiers, keycodes: keycodes })
+    }
+}
+
+pub struct HotkeyGrab {
+    modifiers: u16,
+    keycodes: Vec<xcb_keycode_t>,
+}
+
+impl HotkeyGrab {
+    pub fn matches(&self, event: &xcb_key_press_event_t) -> bool {
+        let ignored = XCB_MOD_MASK_LOCK as u16 | XCB_MOD_MASK_2 as u16;
+        self.keycodes.contains(&event.detail) && event.state & !ignored == self.modifiers
+    }
+}
+
+fn parse_keysym(key: &str) -> Option<xcb_keysym_t> {
+    let lower = key.to_lowercase();
+    let bytes = lower.as_bytes();
+    if bytes.len() == 1 && ((bytes[0] >= b'a' && bytes[0] <= b'z') || (bytes[0] >= b'0' && bytes[0] <= b'9')) {
+        // Latin-1 keysyms are equal to their (lowercase) character codes
+        return Some(bytes[0] as xcb_keysym_t);
+    }
+    if lower.starts_with('f') {
+        if let Ok(n) = lower[1..].parse::<u32>() {
+            if n >= 1 && n <= 24 {
+                return Some(0xffbe + n - 1);
+            }
+        }
+    }
+    match &lower[..] {
+        "space" => Some(0x0020),
+        "tab" => Some(0xff09),
+        "return" | "enter" => Some(0xff0d),
+        "escape" | "esc" => Some(0xff1b),
+        "home" => Some(0xff50),
+        "left" => Some(0xff51),
+        "up" => Some(0xff52),
+        "right" => Some(0xff53),
+        "down" => Some(0xff54),
+        "pause" => Some(0xff13),
+        "print" => Some(0xff61),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffi::*;
+
+    #[test]
+    fn parses_modifiers() {
+        let hotkey = Hotkey::parse("Super+Shift+T").unwrap();
+        assert_eq!(hotkey.modifiers, (XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT) as u16);
+        assert_eq!(hotkey.keysym, 't' as xcb_keysym_t);
+        assert_eq!(hotkey.spec, "Super+Shift+T");
+        assert_eq!(Hotkey::parse("ctrl + ALT + F12").unwrap().modifiers, (XCB_MOD_MASK_CONTROL | XCB_MOD_MASK_1) as u16);
+        assert_eq!(Hotkey::parse("Control+Mod1+Win+Mod4+space").unwrap().modifiers, (XCB_MOD_MASK_CONTROL | XCB_MOD_MASK_1 | XCB_MOD_MASK_4) as u16);
+        assert_eq!(Hotkey::parse("F1").unwrap().modifiers, 0);
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(Hotkey::parse("Super+7").unwrap().keysym, '7' as xcb_keysym_t);
+        assert_eq!(Hotkey::parse("F1").unwrap().keysym, 0xffbe);
+        assert_eq!(Hotkey::parse("F24").unwrap().keysym, 0xffbe + 23);
+        assert_eq!(Hotkey::parse("Alt+Esc").unwrap().keysym, 0xff1b);
+    }
+
+    #[test]
+    fn rejects_unknown_modifiers_and_keys() {
+        assert_eq!(Hotkey::parse("Hyper+T"), Err("Invalid hotkey `Hyper+T`: unknown modifier `Hyper`".to_owned()));
+        assert_eq!(Hotkey::parse("Super+F25"), Err("Invalid hotkey `Super+F25`: unknown key `F25`".to_owned()));
+        assert!(Hotkey::parse("Super+").is_err());
+        assert!(Hotkey::parse("").is_err());
+    }
+
+    #[test]
+    fn grab_ignores_lock_modifiers() {
+        let grab = HotkeyGrab { modifiers: (XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT) as u16, keycodes: vec!(28) };
+        let press = |keycode, state: u32| xcb_key_press_event_t { detail: keycode, state: state as u16, ..Default::default() };
+        assert!(grab.matches(&press(28, XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT)));
+        assert!(grab.matches(&press(28, XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT | XCB_MOD_MASK_LOCK | XCB_MOD_MASK_2)));
+        assert!(!grab.matches(&press(28, XCB_MOD_MASK_4)));
+        assert!(!grab.matches(&press(28, XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT | XCB_MOD_MASK_CONTROL)));
+        assert!(!grab.matches(&press(29, XCB_MOD_MASK_4 | XCB_MOD_MASK_SHIFT)));
+    }
+}
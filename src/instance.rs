@@ -0,0 +1,76 @@
+use xcb::*;
+use ffi::*;
+use std::time::Duration;
+use std::time::Instant;
+
+const REPLACE_TIMEOUT_MS: u64 = 5000;
+const REPLACE_POLL_INTERVAL_MS: i32 = 100;
+
+// Ownership of the _WACOM_OUTPUT_MAPPER_S<screen> selection, which marks the watcher that
+// manages the tablets of a screen, in the same way as window managers use WM_S<screen>
+pub struct InstanceLock {
+    window: xcb_window_t,
+    selection: xcb_atom_t,
+}
+
+impl InstanceLock {
+    // With `replace`, takes the selection over from a running watcher and waits for it to
+    // restore its devices and exit; otherwise fails when the selection is already owned.
+    // Must be called before any events are selected, as other events are discarded.
+    pub fn acquire(connection: &XcbConnection, root: xcb_window_t, replace: bool) -> Result<InstanceLock, XcbError> {
+        let selection_name = format!("_WACOM_OUTPUT_MAPPER_S{}", connection.screen_num);
+        let selection = try!(connection.intern_atom(&selection_name, false));
+        let previous_owner = try!(connection.get_selection_owner(selection));
+        if previous_owner != XCB_WINDOW_NONE {
+            if !replace {
+                return Err(XcbError::LogicError(format!(
+                    "Another instance is already running ({} is owned by window 0x{:x}); use --replace to take over",
+                    selection_name, previous_owner
+                )));
+            }
+            // The previous owner may exit in the meantime, in which case there is nothing to wait for
+            let _ = connection.select_window_events(previous_owner, XCB_EVENT_MASK_STRUCTURE_NOTIFY);
+        }
+
+        let window = try!(connection.create_input_only_window(root));
+        try!(connection.set_selection_owner(window, selection, XCB_TIME_CURRENT_TIME));
+        if try!(connection.get_selection_owner(selection)) != window {
+            return Err(XcbError::LogicError(format!("Failed to acquire {}", selection_name)));
+        }
+
+        if previous_owner != XCB_WINDOW_NONE {
+            info!("Replacing the running instance");
+            if !wait_for_destroy(connection, previous_owner) {
+                warn!("The previous instance did not exit within {} ms, continuing anyway", REPLACE_TIMEOUT_MS);
+            }
+        }
+
+        Ok(InstanceLock { window: window, selection: selection })
+    }
+
+    // True for the SelectionClear event received when another instance takes over
+    pub fn is_lost(&self, event: &xcb_generic_event_t) -> bool {
+        if event.response_type & 0x7f != 29 /* XCB_SELECTION_CLEAR */ {
+            return false;
+        }
+        let clear = unsafe { &*(event as *const _ as *const xcb_selection_clear_event_t) };
+        clear.owner == self.window && clear.selection == self.selection
+    }
+}
+
+fn wait_for_destroy(connection: &XcbConnection, window: xcb_window_t) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(REPLACE_TIMEOUT_MS) {
+        match connection.wait_for_event_timeout(REPLACE_POLL_INTERVAL_MS) {
+            Ok(Some(event)) => if event.response_type & 0x7f == 17 /* XCB_DESTROY_NOTIFY */ {
+                let destroy = unsafe { &*(event.value as *const xcb_destroy_notify_event_t) };
+                if destroy.window == window {
+                    return true;
+                }
+            },
+            Ok(None) => {},
+            Err(_) => return false,
+        }
+    }
+    false
+}
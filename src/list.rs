@@ -0,0 +1,223 @@
+use xcb::*;
+use ffi::xcb_window_t;
+use outputs::get_output_edid;
+use rustc_serialize::json::Json;
+use rustc_serialize::json::ToJson;
+use std::collections::BTreeMap;
+use std::borrow::ToOwned;
+
+// Everything the `list` subcommand prints
+pub struct Listing {
+    pub outputs: Vec<OutputListing>,
+    pub devices: Vec<DeviceListing>,
+}
+
+pub struct OutputListing {
+    pub id: u32,
+    pub name: String,
+    pub connection: &'static str,
+    pub primary: bool,
+    pub mm_width: u32,
+    pub mm_height: u32,
+    pub edid: Option<String>,
+    pub crtc: Option<CrtcListing>,
+}
+
+pub struct CrtcListing {
+    pub id: u32,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub rotation: &'static str,
+    pub reflection: &'static str,
+}
+
+pub struct DeviceListing {
+    pub id: u16,
+    pub name: String,
+    pub device_type: &'static str,
+    pub attachment: u16,
+    pub enabled: bool,
+    pub has_transformation_matrix: bool,
+}
+
+pub fn collect_listing(randr: &XcbRandr, input: &XcbInput, root_window_id: xcb_window_t) -> Result<Listing, XcbError> {
+    let resources = try!(randr.get_screen_resources(root_window_id));
+    let primary_output = randr.get_output_primary(root_window_id).unwrap_or(0);
+
+    let mut outputs = vec!();
+    for output_id in resources.outputs.iter() {
+        let info = try!(randr.get_output_info(&resources, *output_id));
+        let crtc = if info.crtc != 0 {
+            let crtc_info = try!(randr.get_crtc_info(&resources, info.crtc));
+            let orientation = XcbRandrOrientation::from_u16(crtc_info.rotation);
+            Some(CrtcListing {
+                id: crtc_info.id,
+                x: crtc_info.x,
+                y: crtc_info.y,
+                width: crtc_info.width,
+                height: crtc_info.height,
+                rotation: rotation_name(orientation.rotation),
+                reflection: reflection_name(&orientation),
+            })
+        } else {
+            None
+        };
+        outputs.push(OutputListing {
+            id: *output_id,
+            name: info.name.clone(),
+            connection: connection_name(&info.connection),
+            primary: *output_id == primary_output,
+            mm_width: info.mm_width,
+            mm_height: info.mm_height,
+            edid: get_output_edid(randr, *output_id).map(|edid| edid.to_string()),
+            crtc: crtc,
+        });
+    }
+
+    let mut devices = vec!();
+    for device in try!(input.get_devices()).devices.iter() {
+        let properties = try!(input.get_device_properties(device.deviceid));
+        devices.push(DeviceListing {
+            id: device.deviceid,
+            name: device.name.clone(),
+            device_type: device_type_name(device._type),
+            attachment: device.attachment,
+            enabled: device.enabled,
+            has_transformation_matrix: properties.iter().any(|p| p == "Coordinate Transformation Matrix"),
+        });
+    }
+
+    Ok(Listing { outputs: outputs, devices: devices })
+}
+
+pub fn connection_name(connection: &XcbRandrOutputConnectionStatus) -> &'static str {
+    match *connection {
+        XcbRandrOutputConnectionStatus::Connected => "connected",
+        XcbRandrOutputConnectionStatus::Disconnected => "disconnected",
+        XcbRandrOutputConnectionStatus::Unknown => "unknown",
+    }
+}
+
+// Same names as accepted by `type:TYPE` device criteria
+pub fn device_type_name(device_type: u16) -> &'static str {
+    match XcbInputDeviceType::from_u16(device_type) {
+        Some(XcbInputDeviceType::MasterPointer) => "master-pointer",
+        Some(XcbInputDeviceType::MasterKeyboard) => "master-keyboard",
+        Some(XcbInputDeviceType::SlavePointer) => "slave-pointer",
+        Some(XcbInputDeviceType::SlaveKeyboard) => "slave-keyboard",
+        Some(XcbInputDeviceType::FloatingSlave) => "floating",
+        None => "unknown",
+    }
+}
+
+// Same names as used by `xrandr --rotate`
+pub fn rotation_name(rotation: XcbRandrRotation) -> &'static str {
+    match rotation {
+        XcbRandrRotation::Rotate0 => "normal",
+        XcbRandrRotation::Rotate90 => "left",
+        XcbRandrRotation::Rotate180 => "inverted",
+        XcbRandrRotation::Rotate270 => "right",
+    }
+}
+
+// Same names as used by `xrandr --reflect`
+pub fn reflection_name(orientation: &XcbRandrOrientation) -> &'static str {
+    match (orientation.reflect_x, orientation.reflect_y) {
+        (false, false) => "normal",
+        (true, false) => "x",
+        (false, true) => "y",
+        (true, true) => "xy",
+    }
+}
+
+pub fn print_listing(listing: &Listing) {
+    println!("Outputs:");
+    for output in listing.outputs.iter() {
+        let mut details = vec!(output.connection.to_owned());
+        if output.primary {
+            details.push("primary".to_owned());
+        }
+        if let Some(ref crtc) = output.crtc {
+            details.push(format!("{}x{}+{}+{} (CRTC {}, rotation {}, reflection {})", crtc.width, crtc.height, crtc.x, crtc.y, crtc.id, crtc.rotation, crtc.reflection));
+        }
+        if output.mm_width != 0 || output.mm_height != 0 {
+            details.push(format!("{}mm x {}mm", output.mm_width, output.mm_height));
+        }
+        if let Some(ref edid) = output.edid {
+            details.push(format!("edid:{}", edid));
+        }
+        println!("    {} (id {}): {}", output.name, output.id, details.join(", "));
+    }
+    println!("Devices:");
+    for device in listing.devices.iter() {
+        println!(
+            "    {} (id {}): {}, attached to {}, {}{}",
+            device.name,
+            device.id,
+            device.device_type,
+            device.attachment,
+            if device.enabled { "enabled" } else { "disabled" },
+            if device.has_transformation_matrix { ", has Coordinate Transformation Matrix" } else { "" }
+        );
+    }
+}
+
+pub fn print_listing_json(listing: &Listing) {
+    println!("{}", listing.to_json().pretty());
+}
+
+impl ToJson for Listing {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("outputs".to_owned(), self.outputs.iter().map(|o| o.to_json()).collect::<Vec<_>>().to_json());
+        object.insert("devices".to_owned(), self.devices.iter().map(|d| d.to_json()).collect::<Vec<_>>().to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for OutputListing {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_owned(), self.id.to_json());
+        object.insert("name".to_owned(), self.name.to_json());
+        object.insert("connection".to_owned(), self.connection.to_json());
+        object.insert("primary".to_owned(), self.primary.to_json());
+        object.insert("mm_width".to_owned(), self.mm_width.to_json());
+        object.insert("mm_height".to_owned(), self.mm_height.to_json());
+        object.insert("edid".to_owned(), self.edid.to_json());
+        object.insert("crtc".to_owned(), match self.crtc {
+            Some(ref crtc) => crtc.to_json(),
+            None => Json::Null,
+        });
+        Json::Object(object)
+    }
+}
+
+impl ToJson for CrtcListing {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_owned(), self.id.to_json());
+        object.insert("x".to_owned(), (self.x as i64).to_json());
+        object.insert("y".to_owned(), (self.y as i64).to_json());
+        object.insert("width".to_owned(), (self.width as u64).to_json());
+        object.insert("height".to_owned(), (self.height as u64).to_json());
+        object.insert("rotation".to_owned(), self.rotation.to_json());
+        object.insert("reflection".to_owned(), self.reflection.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for DeviceListing {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_owned(), (self.id as u64).to_json());
+        object.insert("name".to_owned(), self.name.to_json());
+        object.insert("type".to_owned(), self.device_type.to_json());
+        object.insert("attachment".to_owned(), (self.attachment as u64).to_json());
+        object.insert("enabled".to_owned(), self.enabled.to_json());
+        object.insert("has_transformation_matrix".to_owned(), self.has_transformation_matrix.to_json());
+        Json::Object(object)
+    }
+}
@@ -0,0 +1,220 @@
+use libc::c_int;
+use std::borrow::ToOwned;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::result::Result;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::atomic::ATOMIC_USIZE_INIT;
+use sys;
+
+const IDENTIFIER: &'static str = "wacom-output-mapping-watcher";
+const SYSLOG_SOCKET: &'static str = "/dev/log";
+const JOURNALD_SOCKET: &'static str = "/run/systemd/journal/socket";
+// LOG_USER
+const SYSLOG_FACILITY: c_int = 1;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub fn from_verbosity(verbose: usize, quiet: usize) -> Level {
+        match Level::Info as isize + verbose as isize - quiet as isize {
+            v if v <= 0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    // Severity as defined by syslog(3), also used by journald's PRIORITY field
+    fn syslog_priority(&self) -> c_int {
+        match *self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = match *self {
+            Level::Error => "error",
+            Level::Warn => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Target {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Option<Target> {
+        match s {
+            "stderr" => Some(Target::Stderr),
+            "syslog" => Some(Target::Syslog),
+            "journald" => Some(Target::Journald),
+            _ => None
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+thread_local!(static SOCKET: RefCell<Option<(Target, UnixDatagram)>> = RefCell::new(None));
+
+// Until init is called, records up to Level::Info go to stderr
+pub fn init(level: Level, target: Target) -> io::Result<()> {
+    MAX_LEVEL.store(level as usize + 1, Ordering::SeqCst);
+    let socket = match target {
+        Target::Stderr => None,
+        Target::Syslog => Some((target, try!(connect(SYSLOG_SOCKET)))),
+        Target::Journald => Some((target, try!(connect(JOURNALD_SOCKET)))),
+    };
+    SOCKET.with(|s| *s.borrow_mut() = socket);
+    Ok(())
+}
+
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = try!(UnixDatagram::unbound());
+    try!(socket.connect(path));
+    Ok(socket)
+}
+
+pub fn enabled(level: Level) -> bool {
+    match MAX_LEVEL.load(Ordering::SeqCst) {
+        0 => level <= Level::Info,
+        max => (level as usize) < max,
+    }
+}
+
+// Writes a record with additional structured fields. Field names follow the journald
+// conventions (upper case, e.g. DEVICE_ID); syslog and stderr get them as KEY=value.
+pub fn log(level: Level, fields: &[(&str, String)], message: &str) {
+    let sent = SOCKET.with(|s| match *s.borrow() {
+        Some((Target::Journald, ref socket)) => socket.send(&journald_record(level, fields, message)).is_ok(),
+        Some((_, ref socket)) => socket.send(syslog_record(level, fields, message).as_bytes()).is_ok(),
+        None => false,
+    });
+    if !sent {
+        let mut line = match level {
+            Level::Info => message.to_owned(),
+            _ => format!("{}: {}", level, message),
+        };
+        if level >= Level::Debug {
+            line.push_str(&format_fields(fields));
+        }
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+}
+
+fn format_fields(fields: &[(&str, String)]) -> String {
+    fields.iter().map(|&(key, ref value)| format!(" {}={}", key, value)).collect()
+}
+
+fn syslog_record(level: Level, fields: &[(&str, String)], message: &str) -> String {
+    let pid = unsafe { sys::getpid() };
+    format!("<{}>{}[{}]: {}{}", SYSLOG_FACILITY * 8 + level.syslog_priority(), IDENTIFIER, pid, message, format_fields(fields))
+}
+
+// Native journald protocol: KEY=value lines, or KEY, a little-endian 64-bit length and the
+// value for values that contain newlines
+fn journald_record(level: Level, fields: &[(&str, String)], message: &str) -> Vec<u8> {
+    let mut record = vec!();
+    let priority = level.syslog_priority().to_string();
+    let standard = [("MESSAGE", message), ("PRIORITY", &priority[..]), ("SYSLOG_IDENTIFIER", IDENTIFIER)];
+    let custom = fields.iter().map(|&(key, ref value)| (key, &value[..]));
+    for (key, value) in standard.iter().cloned().chain(custom) {
+        record.extend(key.as_bytes().iter().cloned());
+        if value.contains('\n') {
+            record.push(b'\n');
+            let len = value.len() as u64;
+            record.extend((0..8).map(|i| (len >> (i * 8)) as u8));
+        } else {
+            record.push(b'=');
+        }
+        record.extend(value.as_bytes().iter().cloned());
+        record.push(b'\n');
+    }
+    record
+}
+
+// log!(Level::Info, "format", args...) or log!(Level::Info, ["KEY" => value, ...], "format", args...)
+macro_rules! log {
+    ($level:expr, [$($key:expr => $value:expr),*], $($arg:tt)+) => (
+        if ::logging::enabled($level) {
+            ::logging::log($level, &[$(($key, $value.to_string())),*], &format!($($arg)+))
+        }
+    );
+    ($level:expr, $($arg:tt)+) => (log!($level, [], $($arg)+));
+}
+
+macro_rules! error { ($($arg:tt)+) => (log!(::logging::Level::Error, $($arg)+)) }
+macro_rules! warn { ($($arg:tt)+) => (log!(::logging::Level::Warn, $($arg)+)) }
+macro_rules! info { ($($arg:tt)+) => (log!(::logging::Level::Info, $($arg)+)) }
+macro_rules! debug { ($($arg:tt)+) => (log!(::logging::Level::Debug, $($arg)+)) }
+macro_rules! trace { ($($arg:tt)+) => (log!(::logging::Level::Trace, $($arg)+)) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sys;
+
+    #[test]
+    fn verbosity_moves_from_info() {
+        assert_eq!(Level::from_verbosity(0, 0), Level::Info);
+        assert_eq!(Level::from_verbosity(1, 0), Level::Debug);
+        assert_eq!(Level::from_verbosity(2, 0), Level::Trace);
+        assert_eq!(Level::from_verbosity(5, 0), Level::Trace);
+        assert_eq!(Level::from_verbosity(0, 1), Level::Warn);
+        assert_eq!(Level::from_verbosity(0, 2), Level::Error);
+        assert_eq!(Level::from_verbosity(0, 5), Level::Error);
+        assert_eq!(Level::from_verbosity(2, 1), Level::Debug);
+    }
+
+    #[test]
+    fn syslog_record_has_priority_identifier_and_fields() {
+        let pid = unsafe { sys::getpid() };
+        // LOG_USER (1) * 8 + LOG_WARNING (4)
+        assert_eq!(syslog_record(Level::Warn, &[("DEVICE_ID", "12".to_owned()), ("DEVICE", "Wacom Pen".to_owned())], "no output"),
+                   format!("<12>wacom-output-mapping-watcher[{}]: no output DEVICE_ID=12 DEVICE=Wacom Pen", pid));
+        assert_eq!(syslog_record(Level::Trace, &[], "details"), format!("<15>wacom-output-mapping-watcher[{}]: details", pid));
+    }
+
+    #[test]
+    fn journald_record_uses_key_value_lines() {
+        let record = journald_record(Level::Info, &[("OUTPUT", "DP-1".to_owned())], "Active outputs: DP-1");
+        assert_eq!(&record[..], &b"MESSAGE=Active outputs: DP-1\nPRIORITY=6\nSYSLOG_IDENTIFIER=wacom-output-mapping-watcher\nOUTPUT=DP-1\n"[..]);
+    }
+
+    #[test]
+    fn journald_record_frames_multi_line_values() {
+        let record = journald_record(Level::Error, &[("MATRIX", "1 0\n0 1".to_owned())], "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend([9, 0, 0, 0, 0, 0, 0, 0].iter().cloned());
+        expected.extend(b"two\nlines\nPRIORITY=3\nSYSLOG_IDENTIFIER=wacom-output-mapping-watcher\nMATRIX\n".iter().cloned());
+        expected.extend([7, 0, 0, 0, 0, 0, 0, 0].iter().cloned());
+        expected.extend(b"1 0\n0 1\n".iter().cloned());
+        assert_eq!(record, expected);
+    }
+}
@@ -1,9 +1,34 @@
 extern crate getopts;
 extern crate libc;
+extern crate toml;
+extern crate regex;
+extern crate rustc_serialize;
 
 use xcb::*;
+use config::*;
+use matcher::DeviceMatcher;
+use mapping::*;
+use outputs::*;
+use follow::PointerFollower;
+use focus::FocusTracker;
+use hotkey::Hotkey;
+use events::EventLog;
+use instance::InstanceLock;
+use control::ControlCommand;
+use control::ControlServer;
+use dbus_service::MappingSnapshot;
+use dbus_service::OutputGeometry;
+use dbus_service::MapperService;
+use config_watch::ConfigWatcher;
 use getopts::Options;
 use std::env;
+use std::borrow::ToOwned;
+use std::path::PathBuf;
+use std::process;
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 // FFI is build with:
 // LD_PRELOAD=/usr/lib/libclang.so ./bindgen -lxcb -lxcb-randr -lxcb-xinput -I /usr/lib/clang/3.5.0/include -match /usr/include/xcb/ -o ~/develop/rust-wacom-randr/src/ffi.rs ~/develop/rust-wacom-randr/src/ffi-input.h
@@ -19,11 +44,63 @@ mod ffi {
     
     include!("ffi.rs");
 }
+#[macro_use]
+mod logging;
 mod xcb;
+mod config;
+mod matcher;
+mod mapping;
+mod outputs;
+mod edid;
+mod follow;
+mod focus;
+mod sys;
+mod hotkey;
+mod signals;
+mod list;
+mod events;
+mod instance;
+mod control;
+mod dbus;
+mod dbus_service;
+mod config_watch;
+
+const POINTER_POLL_INTERVAL_MS: i32 = 50;
+// Writes of a matrix that does not stick, before giving up on the device
+const MATRIX_ATTEMPTS: u32 = 5;
+// Delay before the first retry, doubled for each further one
+const MATRIX_RETRY_DELAY_MS: u64 = 100;
+// A burst of events that never pauses delays the evaluation by at most this many debounce
+// intervals, counted from its first event
+const MAX_DEBOUNCE_INTERVALS: u32 = 10;
 
 struct CliOptions {
+    // Subcommand given as the first free argument
+    pub command: Option<String>,
+    pub command_args: Vec<String>,
+    pub json: bool,
     pub watch: bool,
-    pub output: String,
+    pub no_restore: bool,
+    pub replace: bool,
+    pub show: bool,
+    pub dry_run: bool,
+    pub events_json: Option<String>,
+    pub verbose: usize,
+    pub quiet: usize,
+    pub log: Option<String>,
+    pub output: Option<String>,
+    pub area: Option<String>,
+    pub config: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub keep_aspect: Option<String>,
+    pub on_missing: Option<String>,
+    pub follow: Option<String>,
+    pub dwell: Option<String>,
+    pub hysteresis: Option<String>,
+    pub debounce: Option<String>,
+    pub clip_to_output: bool,
+    pub cycle_key: Option<String>,
 }
 
 fn parse_options() -> Option<CliOptions> {
@@ -34,105 +111,834 @@ fn parse_options() -> Option<CliOptions> {
     let mut opts = Options::new();
     
     opts.optflag("w", "watch", "watch for RANDR events and reconfigure Wacom tablets");
-    opts.optopt("o", "output", "name of X RANDR output to which Wacom tables will be mapped", "OUTPUT");
+    opts.optflag("", "no-restore", "in watch mode, do not restore the original transformation matrices on exit");
+    opts.optflag("", "replace", "in watch mode, take over from an already running instance instead of refusing to start");
+    opts.optflag("s", "show", "print the current transformation matrix of every matched device and exit");
+    opts.optflag("n", "dry-run", "print the transformation matrices that would be set, but do not change any device");
+    opts.optopt("o", "output", "X RANDR output to which Wacom tables will be mapped: output name, edid:MANUFACTURER[:MODEL[:SERIAL]], primary, builtin, external, leftmost, rightmost, largest or largest-physical; a comma-separated list is tried in order", "OUTPUT[,OUTPUT...]");
+    opts.optmulti("i", "include", "select devices matching SPEC: name:REGEX, id:VENDOR:PRODUCT or type:TYPE (default: name:^Wacom)", "SPEC");
+    opts.optmulti("x", "exclude", "skip devices matching SPEC (same syntax as --include)", "SPEC");
+    opts.optopt("", "area", "screen area to which Wacom tablets will be mapped: WxH+X+Y in pixels, or OUTPUT:WxH+X+Y where components may be percents of the output (e.g. DP-1:50%x100%+0+0)", "AREA");
+    opts.optflagopt("a", "keep-aspect", "preserve the tablet aspect ratio by cropping the tablet area (crop) or letterboxing the output area (letterbox, the default)", "MODE");
+    opts.optopt("", "on-missing", "what to do when none of the outputs is active: next (use the first active output, the default), span (map to all outputs), keep (leave the current mapping) or disable (disable the device)", "POLICY");
+    opts.optopt("f", "follow", "map tablets to the output that contains the pointer (pointer) or to the focused window (window); implies --watch", "pointer|window");
+    opts.optopt("", "dwell", "time the pointer has to stay on another output before --follow switches to it (default: 300)", "MS");
+    opts.optopt("", "hysteresis", "distance from the edges of another output the pointer has to reach before --follow switches to it (default: 20)", "PIXELS");
+    opts.optopt("", "debounce", "in watch mode, time to wait for a burst of output and device changes to end before reconfiguring tablets (default: 150)", "MS");
+    opts.optflag("", "clip-to-output", "with --follow window, map tablets only to the part of the window on the output it sits on");
+    opts.optflagopt("k", "cycle-key", "in watch mode, grab KEYS (default: Super+Shift+T) to cycle tablets through the active outputs and all outputs", "KEYS");
+    opts.optopt("c", "config", "path to the configuration file (default: $XDG_CONFIG_HOME/wacom-output-mapping-watcher/config.toml)", "PATH");
+    opts.optflagopt("", "events-json", "write a JSON object per line to PATH (default: stdout) for every RANDR change, device hierarchy change, output layout change and applied matrix", "PATH");
+    opts.optflag("", "json", "with list, print JSON instead of text");
+    opts.optflagmulti("v", "verbose", "log more details (debug, -vv for trace)");
+    opts.optflagmulti("q", "quiet", "log only warnings (-qq for errors only)");
+    opts.optopt("", "log", "where to log: stderr (the default), syslog or journald", "TARGET");
     opts.optflag("h", "help", "print this help menu");
     
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
         Err(f) => { panic!(f.to_string()) }
     };
-    if matches.opt_present("h") || !matches.opt_present("o") {
-        let brief = format!("Usage: {} [options]", program);
+    if matches.opt_present("h") {
+        let brief = format!("Usage: {} [options]\n       {} list [--json]\n       {} ctl COMMAND [ARGS]", program, program, program);
         print!("{}", opts.usage(&brief));
         return None;
     }
     
+    let command = match matches.free.len() {
+        0 => None,
+        1 if matches.free[0] == "list" => Some(matches.free[0].clone()),
+        n if n > 1 && matches.free[0] == "ctl" => Some(matches.free[0].clone()),
+        _ => {
+            error!("Unknown command `{}` (see --help)", matches.free.join(" "));
+            process::exit(1);
+        }
+    };
+    
     return Some(CliOptions {
+        command: command,
+        command_args: matches.free.iter().skip(1).cloned().collect(),
+        json: matches.opt_present("json"),
         watch: matches.opt_present("w"),
-        output: matches.opt_str("o").unwrap()
+        no_restore: matches.opt_present("no-restore"),
+        replace: matches.opt_present("replace"),
+        show: matches.opt_present("s"),
+        dry_run: matches.opt_present("n"),
+        verbose: matches.opt_count("v"),
+        quiet: matches.opt_count("q"),
+        log: matches.opt_str("log"),
+        events_json: if matches.opt_present("events-json") {
+            Some(matches.opt_str("events-json").unwrap_or("-".to_owned()))
+        } else {
+            None
+        },
+        output: matches.opt_str("o"),
+        area: matches.opt_str("area"),
+        config: matches.opt_str("c"),
+        include: matches.opt_strs("i"),
+        exclude: matches.opt_strs("x"),
+        on_missing: matches.opt_str("on-missing"),
+        follow: matches.opt_str("f"),
+        dwell: matches.opt_str("dwell"),
+        hysteresis: matches.opt_str("hysteresis"),
+        debounce: matches.opt_str("debounce"),
+        clip_to_output: matches.opt_present("clip-to-output"),
+        cycle_key: if matches.opt_present("k") {
+            Some(matches.opt_str("k").unwrap_or(DEFAULT_CYCLE_KEY.to_owned()))
+        } else {
+            None
+        },
+        keep_aspect: if matches.opt_present("a") {
+            Some(matches.opt_str("a").unwrap_or("letterbox".to_owned()))
+        } else {
+            None
+        },
     });
 }
 
-fn get_active_outputs(randr: &XcbRandr, resources: &XcbScreenResources) -> Vec<(XcbRandrOutputInfo, XcbRandrCrtcInfo)> {
-    let result = resources
-        .outputs
-        .iter()
-        .map(|output_id| randr.get_output_info(resources, *output_id).unwrap())
-        .filter(|output_info| if let XcbRandrOutputConnectionStatus::Connected = output_info.connection { true } else { false })
-        .filter(|output_info| output_info.crtc != 0)
-        .map(|output_info| {
-            let crtc_info = randr.get_crtc_info(resources, output_info.crtc).unwrap();
-            (output_info, crtc_info)
-        })
-        .collect();
-    result
-}
-
-#[derive(Debug,PartialEq,Eq)]
-pub struct XcbOutputDescription {
-    pub name: String,
-    pub x: i16,
-    pub y: i16,
-    pub width: u16,
-    pub height: u16,
-}
-
-fn describe_output_and_crtc(x: &(XcbRandrOutputInfo, XcbRandrCrtcInfo)) -> XcbOutputDescription {
-    XcbOutputDescription {
-        name: x.0.name.clone(),
-        x: x.1.x,
-        y: x.1.y,
-        width: x.1.width,
-        height: x.1.height,
-    }
-}
-
-fn update_wacom_tablets(connection: &XcbConnection, input: &XcbInput, outputs: &[XcbOutputDescription], to_out_name: &str) {
-    //println!("update_wacom_tablets(_, _, {:?}, {:?})", outputs, to_out_name);
-    let to_out_opt = outputs.iter().filter(|o| o.name == to_out_name).nth(0);
-    let to_out = match to_out_opt {
-        Some(out) => out,
-        None => match outputs.get(0) {
-            Some(out) => out,
+// --config, or the default configuration file if it exists
+fn config_file_path(options: &CliOptions) -> Option<PathBuf> {
+    match options.config {
+        Some(ref path) => Some(PathBuf::from(path)),
+        None => match default_config_path() {
+            Some(ref path) if path.exists() => Some(path.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn load_configuration(options: &CliOptions) -> Option<Config> {
+    let target = match (&options.output, &options.area) {
+        (&Some(ref output), &None) => match OutputSelector::parse_list(&output.split(',').collect::<Vec<_>>()) {
+            Ok(selectors) => Some(Target::Output(selectors)),
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        },
+        (&None, &Some(ref area)) => match Geometry::parse(area) {
+            Ok(geometry) => Some(Target::Area(geometry)),
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        },
+        (&Some(_), &Some(_)) => {
+            error!("--output and --area are mutually exclusive");
+            return None;
+        }
+        (&None, &None) => None,
+    };
+    
+    let config_path = config_file_path(options);
+    // Following the pointer overrides the target and --show does not use it, so a rule is
+    // not strictly needed
+    let target = match (target, &config_path) {
+        (None, &None) if options.follow.is_some() || options.show => Some(Target::Output(vec!(OutputSelector::Primary))),
+        (target, _) => target,
+    };
+    
+    let mut config = if let Some(target) = target {
+        let matcher = match DeviceMatcher::parse(&options.include, &options.exclude) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        };
+        let aspect = match options.keep_aspect {
+            Some(ref mode) => match AspectMode::parse(mode) {
+                Some(aspect) => aspect,
+                None => {
+                    error!("Invalid --keep-aspect mode `{}`: expected crop or letterbox", mode);
+                    return None;
+                }
+            },
+            None => AspectMode::Stretch,
+        };
+        let on_missing = match options.on_missing {
+            Some(ref policy) => match MissingPolicy::parse(policy) {
+                Some(on_missing) => on_missing,
+                None => {
+                    error!("Invalid --on-missing policy `{}`: expected next, span, keep or disable", policy);
+                    return None;
+                }
+            },
+            None => MissingPolicy::Next,
+        };
+        Config::with_rules(vec!(Rule { matcher: matcher, target: target, aspect: aspect, on_missing: on_missing }))
+    } else {
+        let path = match config_path {
+            Some(path) => path,
             None => {
-                //println!("All outputs are disabled");
-                return;
+                error!("Either --output, --area or a configuration file is required (see --help)");
+                return None;
+            }
+        };
+        match load_config(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to load {}: {}", path.display(), e);
+                return None;
             }
         }
     };
-    let min_x: f32 = outputs.iter().map(|o| o.x).min().unwrap() as f32;
-    let min_y: f32 = outputs.iter().map(|o| o.y).min().unwrap() as f32;
-    let max_x: f32 = outputs.iter().map(|o| o.x + o.width as i16).max().unwrap() as f32;
-    let max_y: f32 = outputs.iter().map(|o| o.y + o.height as i16).max().unwrap() as f32;
     
-    let dx = (to_out.x as f32 - min_x) / (max_x - min_x);
-    let dy = (to_out.y as f32 - min_y) / (max_y - min_y);
-    let cx = to_out.width as f32 / (max_x - min_x);
-    let cy = to_out.height as f32 / (max_y - min_y);
+    config.watch = config.watch || options.watch;
+    config.restore = config.restore && !options.no_restore;
+    if let Some(ref mode) = options.follow {
+        match FollowMode::parse(mode) {
+            Some(follow) => config.follow = Some(follow),
+            None => {
+                error!("Invalid --follow mode `{}`: expected pointer or window", mode);
+                return None;
+            }
+        }
+    }
+    if let Some(ref dwell) = options.dwell {
+        match dwell.parse::<i64>().map_err(|_| "expected a number of milliseconds".to_owned()).and_then(|value| check_non_negative("dwell", value).map(|_| value)) {
+            Ok(value) => config.dwell_ms = value as u64,
+            Err(e) => {
+                error!("Invalid --dwell `{}`: {}", dwell, e);
+                return None;
+            }
+        }
+    }
+    if let Some(ref hysteresis) = options.hysteresis {
+        match hysteresis.parse::<i64>().map_err(|_| "expected a number of pixels".to_owned()).and_then(|value| check_non_negative("hysteresis", value).map(|_| value)) {
+            Ok(value) => config.hysteresis = value as i32,
+            Err(e) => {
+                error!("Invalid --hysteresis `{}`: {}", hysteresis, e);
+                return None;
+            }
+        }
+    }
+    if let Some(ref debounce) = options.debounce {
+        match debounce.parse::<i64>().map_err(|_| "expected a number of milliseconds".to_owned()).and_then(|value| check_non_negative("debounce", value).map(|_| value)) {
+            Ok(value) => config.debounce_ms = value as u64,
+            Err(e) => {
+                error!("Invalid --debounce `{}`: {}", debounce, e);
+                return None;
+            }
+        }
+    }
+    config.clip_to_output = config.clip_to_output || options.clip_to_output;
+    if let Some(ref spec) = options.cycle_key {
+        match Hotkey::parse(spec) {
+            Ok(hotkey) => config.cycle_key = Some(hotkey),
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        }
+    }
+    if config.follow.is_some() {
+        config.watch = true;
+    }
+    Some(config)
+}
+
+// Re-reads the configuration file, or the rules given on the command line. Only the rules
+// and `restore` are replaced; the other settings keep the values the watcher started with,
+// with a warning if they have changed. An invalid configuration is logged and the current
+// one is kept.
+fn reload_configuration(options: &CliOptions, config: &mut Config) -> bool {
+    match load_configuration(options) {
+        Some(new_config) => {
+            info!("Configuration reloaded: {} rule(s)", new_config.rules.len());
+            let unapplied = [
+                ("follow", new_config.follow != config.follow),
+                ("cycle-key", new_config.cycle_key != config.cycle_key),
+                ("debounce", new_config.debounce_ms != config.debounce_ms),
+                ("dwell", new_config.dwell_ms != config.dwell_ms),
+                ("hysteresis", new_config.hysteresis != config.hysteresis),
+                ("clip-to-output", new_config.clip_to_output != config.clip_to_output),
+            ];
+            for &(key, changed) in unapplied.iter() {
+                if changed {
+                    warn!("The new value of `{}` only takes effect after a restart", key);
+                }
+            }
+            config.rules = new_config.rules;
+            config.restore = new_config.restore;
+            true
+        },
+        None => {
+            warn!("Keeping the previous configuration");
+            false
+        },
+    }
+}
+
+enum MappingAction {
+    // Matrix, the name of the output it maps to, if any, and for AspectMode::Crop the aspect
+    // ratio (in tablet orientation) of the part of the tablet to use
+    Apply(Vec<f32>, Option<String>, Option<f32>),
+    Keep,
+    Disable,
+}
+
+struct MappingState {
+    // Devices disabled because of MissingPolicy::Disable
+    disabled_devices: Vec<ffi::xcb_input_device_id_t>,
+    // Target that takes precedence over the targets of all rules (e.g. when following the pointer)
+    target_override: Option<Target>,
+    // Only print what would be changed
+    dry_run: bool,
+    // Coordinate Transformation Matrix of each device before it was first updated
+    original_matrices: Vec<(ffi::xcb_input_device_id_t, String, Vec<f32>)>,
+    // Wacom Tablet Area of each device before it was first cropped
+    original_areas: Vec<(ffi::xcb_input_device_id_t, String, Vec<i32>)>,
+    // Atoms of the Coordinate Transformation Matrix property and of its type, interned once
+    matrix_atom: ffi::xcb_atom_t,
+    float_atom: ffi::xcb_atom_t,
+    // Atom of the Wacom Tablet Area property, XCB_NONE without xf86-input-wacom
+    area_atom: ffi::xcb_atom_t,
+    // Devices whose matrix has to be written again
+    retries: Vec<MatrixRetry>,
+    // Destination of --events-json
+    events: Option<EventLog>,
+}
+
+// A freshly plugged device may lack the Coordinate Transformation Matrix for a moment, or
+// its driver may reset the matrix right after it was set
+struct MatrixRetry {
+    device_id: ffi::xcb_input_device_id_t,
+    // Attempts made so far
+    attempts: u32,
+    due: Instant,
+}
+
+// `can_crop` tells whether the device has a tablet area that AspectMode::Crop can reduce
+fn compute_mapping(outputs: &[XcbOutputDescription], rule: &Rule, target: &Target, device: &XcbInputDevice, can_crop: bool) -> MappingAction {
+    if outputs.is_empty() {
+        return MappingAction::Keep;
+    }
+    let output_rects: Vec<_> = outputs.iter().map(|o| o.rect()).collect();
+    let bounds = Rect::bounding_box(&output_rects).unwrap();
+    
+    let selectors: Vec<OutputSelector> = match *target {
+        Target::Output(ref selectors) => selectors.clone(),
+        Target::Area(ref geometry) => geometry.output.iter().cloned().collect(),
+    };
+    let to_out = if selectors.is_empty() {
+        None
+    } else {
+        match find_output(outputs, &selectors) {
+            Some(out) => Some(out),
+            None => match rule.on_missing {
+                MissingPolicy::Next => {
+                    warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name, "OUTPUT" => outputs[0].name],
+                          "{}: {} is not active, using {} (on-missing: {})", device.name, target, outputs[0].name, rule.on_missing);
+                    outputs.get(0)
+                }
+                MissingPolicy::Span => {
+                    warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+                          "{}: {} is not active, mapping to all outputs (on-missing: {})", device.name, target, rule.on_missing);
+                    return MappingAction::Apply(identity_matrix(), None, None);
+                }
+                MissingPolicy::Keep => {
+                    warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+                          "{}: {} is not active, leaving the mapping untouched (on-missing: {})", device.name, target, rule.on_missing);
+                    return MappingAction::Keep;
+                }
+                MissingPolicy::Disable => {
+                    warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+                          "{}: {} is not active, disabling the device (on-missing: {})", device.name, target, rule.on_missing);
+                    return MappingAction::Disable;
+                }
+            }
+        }
+    };
     
-    let transform_matrix = vec!(
-         cx,  0.0,   dx,
-        0.0,   cy,   dy,
-        0.0,  0.0,  1.0
-    );
+    let (area, orientation) = match (target, to_out) {
+        (&Target::Area(ref geometry), Some(out)) => (geometry.to_rect(&out.rect()), out.orientation),
+        (&Target::Area(ref geometry), None) => (geometry.to_rect(&bounds), XcbRandrOrientation::from_u16(ffi::XCB_RANDR_ROTATION_ROTATE_0 as u16)),
+        (&Target::Output(_), Some(out)) => (out.rect(), out.orientation),
+        (&Target::Output(_), None) => unreachable!(),
+    };
     
-    println!("New Coordinate Transformation Matrix = {:?}", transform_matrix);
+    let aspect_mode = if rule.aspect == AspectMode::Crop && !can_crop {
+        debug!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "{} has no Wacom Tablet Area to crop, letterboxing instead", device.name);
+        AspectMode::Letterbox
+    } else {
+        rule.aspect
+    };
+    let mapped_area = match tablet_aspect(device) {
+        Some(aspect) => fit_aspect(area, oriented_aspect(aspect, orientation), aspect_mode),
+        None => area,
+    };
+    let crop_aspect = if aspect_mode == AspectMode::Crop { Some(oriented_aspect(area.width / area.height, orientation)) } else { None };
     
-    for device in input.get_devices().unwrap().devices.iter() {
-        if device.name.starts_with("Wacom") {
-            for property in input.get_device_properties(device.deviceid).unwrap().iter() {
-                if property == "Coordinate Transformation Matrix" {
-                    let property_name_atom = connection.intern_atom(property, true).unwrap();
-                    let property_type_atom = connection.intern_atom("FLOAT", true).unwrap();
-                    println!("Updating {}", device.name);
-                    input.set_property_value(device.deviceid, property_name_atom, property_type_atom, 32, &transform_matrix).unwrap();
-                    
-                    /*{
-                        let data = input.get_property_value::<f32>(device.deviceid, property_name_atom);
-                        println!("    Value data: {}", data);
-                    }*/
+    MappingAction::Apply(transform_matrix(&mapped_area, &bounds, orientation), to_out.map(|out| out.name.clone()), crop_aspect)
+}
+
+// Target after `current` in the cycle: each active output in turn, then all outputs
+fn next_cycle_target(outputs: &[XcbOutputDescription], current: Option<&Target>) -> Target {
+    let all_outputs = Target::Area(Geometry {
+        output: None,
+        width: Length::Percent(100.0),
+        height: Length::Percent(100.0),
+        x: Length::Percent(0.0),
+        y: Length::Percent(0.0),
+    });
+    let next_idx = match current {
+        Some(target) if *target == all_outputs => 0,
+        Some(&Target::Output(ref selectors)) => match find_output(outputs, selectors) {
+            Some(out) => outputs.iter().position(|o| o.name == out.name).unwrap() + 1,
+            None => 0,
+        },
+        _ => 0,
+    };
+    match outputs.get(next_idx) {
+        Some(out) => Target::Output(vec!(OutputSelector::Name(out.name.clone()))),
+        None => all_outputs,
+    }
+}
+
+// Target for the rectangle of the focused window, optionally clipped to the output that
+// holds most of it. None (i.e. the targets of the rules) when no window is focused.
+fn window_target(outputs: &[XcbOutputDescription], rect: Option<Rect>, clip_to_output: bool) -> Option<Target> {
+    let output_rects: Vec<_> = outputs.iter().map(|o| o.rect()).collect();
+    let rect = match rect {
+        Some(rect) if !output_rects.is_empty() => rect,
+        _ => return None,
+    };
+    let area = |r: &Rect| r.width * r.height;
+    let rect = if clip_to_output {
+        output_rects.iter()
+            .filter_map(|o| rect.intersect(o))
+            .fold(None, |best: Option<Rect>, r| match best {
+                Some(b) if area(&b) >= area(&r) => Some(b),
+                _ => Some(r),
+            })
+            .unwrap_or(rect)
+    } else {
+        rect
+    };
+    Some(Target::Area(Geometry {
+        output: None,
+        width: Length::Pixels(rect.width),
+        height: Length::Pixels(rect.height),
+        x: Length::Pixels(rect.x),
+        y: Length::Pixels(rect.y),
+    }))
+}
+
+// First rule that matches the device
+fn find_rule<'a>(input: &XcbInput, rules: &'a [Rule], device: &XcbInputDevice) -> Option<&'a Rule> {
+    let needs_product_id = rules.iter().any(|r| r.matcher.needs_product_id());
+    let product_id = if needs_product_id {
+        match input.get_device_product_id(device.deviceid) {
+            Ok(product_id) => product_id,
+            Err(e) => {
+                // E.g. BadDevice when the device has just been unplugged
+                warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to read the product id of {}: {:?}", device.name, e);
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    rules.iter().filter(|r| r.matcher.matches(device, product_id)).nth(0)
+}
+
+fn update_wacom_tablets(input: &XcbInput, outputs: &[XcbOutputDescription], rules: &[Rule], state: &mut MappingState) {
+    let devices = match input.get_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Failed to list the input devices: {:?}", e);
+            return;
+        }
+    };
+    for device in devices.devices.iter() {
+        update_wacom_tablet(input, outputs, rules, state, device, false);
+    }
+}
+
+// Applies the first rule that matches `device`, if any. For a `just_added` device, a missing
+// matrix property is retried as well.
+fn update_wacom_tablet(input: &XcbInput, outputs: &[XcbOutputDescription], rules: &[Rule], state: &mut MappingState,
+                       device: &XcbInputDevice, just_added: bool) {
+    let attempts = match state.retries.iter().position(|r| r.device_id == device.deviceid) {
+        Some(idx) => state.retries.remove(idx).attempts,
+        None => 0,
+    };
+    let rule = match find_rule(input, rules, device) {
+        Some(rule) => rule,
+        None => {
+            trace!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "{}: no rule matches", device.name);
+            return;
+        }
+    };
+    // Cloned, as `state` is updated while the target is still needed
+    let target = state.target_override.as_ref().unwrap_or(&rule.target).clone();
+    let property_atoms = match input.get_device_property_atoms(device.deviceid) {
+        Ok(atoms) => atoms,
+        Err(e) => {
+            warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to list the properties of {}: {:?}", device.name, e);
+            return;
+        }
+    };
+    let can_crop = state.area_atom != 0 /* XCB_NONE */ && property_atoms.contains(&state.area_atom);
+    let (transform_matrix, output_name, crop_aspect) = match compute_mapping(outputs, rule, &target, device, can_crop) {
+        MappingAction::Apply(matrix, output_name, crop_aspect) => (matrix, output_name.unwrap_or(String::new()), crop_aspect),
+        MappingAction::Keep => return,
+        MappingAction::Disable => {
+            if state.dry_run {
+                println!("Would disable {}", device.name);
+            } else if device.enabled {
+                // E.g. BadDevice when the device has just been unplugged
+                match input.set_device_enabled(device.deviceid, false) {
+                    Ok(_) => state.disabled_devices.push(device.deviceid),
+                    Err(e) => warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to disable {}: {:?}", device.name, e),
+                }
+            }
+            return;
+        }
+    };
+    if let Some(idx) = state.disabled_devices.iter().position(|id| *id == device.deviceid) {
+        info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Re-enabling {}", device.name);
+        if let Err(e) = input.set_device_enabled(device.deviceid, true) {
+            warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to re-enable {}: {:?}", device.name, e);
+            return;
+        }
+        state.disabled_devices.remove(idx);
+    }
+    if can_crop {
+        update_tablet_area(input, state, device, crop_aspect);
+    }
+    if !property_atoms.contains(&state.matrix_atom) {
+        if (just_added || attempts > 0) && !state.dry_run {
+            schedule_matrix_retry(state, device, attempts + 1, "the device has no Coordinate Transformation Matrix");
+        }
+        return;
+    }
+    if state.dry_run {
+        let current = input.get_property_value::<f32>(device.deviceid, state.matrix_atom, state.float_atom);
+        println!("Would update {} ({}): Coordinate Transformation Matrix = {:?}", device.name, target, transform_matrix);
+        match current {
+            Ok(matrix) => println!("    current value: {:?}", matrix),
+            Err(e) => println!("    current value could not be read: {:?}", e),
+        }
+        return;
+    }
+    if !state.original_matrices.iter().any(|&(id, _, _)| id == device.deviceid) {
+        match input.get_property_value::<f32>(device.deviceid, state.matrix_atom, state.float_atom) {
+            Ok(matrix) => state.original_matrices.push((device.deviceid, device.name.clone(), matrix)),
+            Err(e) => warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+                            "Failed to read the Coordinate Transformation Matrix of {}: {:?}", device.name, e),
+        }
+    }
+    info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name, "OUTPUT" => output_name, "TARGET" => target, "MATRIX" => format!("{:?}", transform_matrix)],
+          "Updating {} ({}): Coordinate Transformation Matrix = {:?}", device.name, target, transform_matrix);
+    let written = input.set_property_value(device.deviceid, state.matrix_atom, state.float_atom, 32, &transform_matrix)
+        .and_then(|_| input.get_property_value::<f32>(device.deviceid, state.matrix_atom, state.float_atom));
+    match written {
+        Ok(ref matrix) if *matrix == transform_matrix => {
+            if attempts > 0 {
+                info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "The matrix of {} stuck after {} retries", device.name, attempts);
+            }
+        },
+        Ok(matrix) => {
+            let reason = format!("the matrix reads back as {:?}", matrix);
+            schedule_matrix_retry(state, device, attempts + 1, &reason);
+            return;
+        },
+        Err(e) => {
+            let reason = format!("the matrix could not be written: {:?}", e);
+            schedule_matrix_retry(state, device, attempts + 1, &reason);
+            return;
+        },
+    }
+    if let Some(ref mut events) = state.events {
+        events.matrix_applied(device, &target, &transform_matrix);
+    }
+}
+
+// Crops the Wacom Tablet Area of `device` to `crop_aspect`, or resets it to its original value
+// when the device is not (or no longer) cropped
+fn update_tablet_area(input: &XcbInput, state: &mut MappingState, device: &XcbInputDevice, crop_aspect: Option<f32>) {
+    let original = match state.original_areas.iter().find(|&&(id, _, _)| id == device.deviceid) {
+        Some(&(_, _, ref area)) => area.clone(),
+        None if crop_aspect.is_none() => return,
+        None => match input.get_property_value::<i32>(device.deviceid, state.area_atom, ffi::XCB_ATOM_INTEGER) {
+            Ok(ref area) if area.len() == 4 => {
+                if !state.dry_run {
+                    state.original_areas.push((device.deviceid, device.name.clone(), area.clone()));
                 }
+                area.clone()
+            },
+            Ok(area) => {
+                warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Unexpected Wacom Tablet Area of {}: {:?}", device.name, area);
+                return;
+            },
+            Err(e) => {
+                warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to read the Wacom Tablet Area of {}: {:?}", device.name, e);
+                return;
+            },
+        },
+    };
+    let area = match crop_aspect {
+        Some(aspect) => {
+            let full = Rect { x: original[0] as f32, y: original[1] as f32, width: (original[2] - original[0]) as f32, height: (original[3] - original[1]) as f32 };
+            let cropped = crop_area(device, full, aspect);
+            vec!(cropped.x.round() as i32, cropped.y.round() as i32, (cropped.x + cropped.width).round() as i32, (cropped.y + cropped.height).round() as i32)
+        },
+        None => original,
+    };
+    if state.dry_run {
+        println!("Would update {}: Wacom Tablet Area = {:?}", device.name, area);
+        return;
+    }
+    info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Updating {}: Wacom Tablet Area = {:?}", device.name, area);
+    if let Err(e) = input.set_property_value(device.deviceid, state.area_atom, ffi::XCB_ATOM_INTEGER, 32, &area) {
+        warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Failed to set the Wacom Tablet Area of {}: {:?}", device.name, e);
+    }
+}
+
+// Updates `device` again after an exponential backoff, unless it has failed MATRIX_ATTEMPTS times
+fn schedule_matrix_retry(state: &mut MappingState, device: &XcbInputDevice, attempts: u32, reason: &str) {
+    if attempts >= MATRIX_ATTEMPTS {
+        warn!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+              "Giving up on {} after {} attempts: {}", device.name, attempts, reason);
+        return;
+    }
+    let delay = Duration::from_millis(MATRIX_RETRY_DELAY_MS << (attempts - 1));
+    debug!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name],
+           "Retrying {} in {} ms: {}", device.name, millis_ceil(delay), reason);
+    state.retries.push(MatrixRetry { device_id: device.deviceid, attempts: attempts, due: Instant::now() + delay });
+}
+
+// Updates the devices whose retry is due; returns the time until the next one
+fn run_matrix_retries(input: &XcbInput, outputs: &[XcbOutputDescription], rules: &[Rule], state: &mut MappingState) -> Option<Duration> {
+    let now = Instant::now();
+    let due: Vec<_> = state.retries.iter().filter(|r| r.due <= now).map(|r| r.device_id).collect();
+    for device_id in due.into_iter() {
+        match input.get_device(device_id).unwrap() {
+            Some(device) => update_wacom_tablet(input, outputs, rules, state, &device, false),
+            None => state.retries.retain(|r| r.device_id != device_id),
+        }
+    }
+    let now = Instant::now();
+    state.retries.iter().map(|r| if r.due > now { r.due - now } else { Duration::from_millis(0) }).min()
+}
+
+// Prints the current Coordinate Transformation Matrix of every device matched by a rule
+fn show_wacom_tablets(connection: &XcbConnection, input: &XcbInput, rules: &[Rule]) {
+    let property_name_atom = connection.intern_atom("Coordinate Transformation Matrix", true).unwrap();
+    let property_type_atom = connection.intern_atom("FLOAT", true).unwrap();
+    for device in input.get_devices().unwrap().devices.iter() {
+        if find_rule(input, rules, device).is_none() {
+            continue;
+        }
+        match input.get_property_value::<f32>(device.deviceid, property_name_atom, property_type_atom) {
+            Ok(matrix) => println!("{}: Coordinate Transformation Matrix = {:?}", device.name, matrix),
+            Err(e) => println!("{}: failed to read the Coordinate Transformation Matrix: {:?}", device.name, e),
+        }
+    }
+}
+
+fn output_names(outputs: &[XcbOutputDescription]) -> String {
+    let names: Vec<_> = outputs.iter().map(|o| &o.name[..]).collect();
+    names.join(", ")
+}
+
+// Logs the EDID identity of each output, in the form accepted by EDID selectors
+fn log_output_identities(outputs: &[XcbOutputDescription]) {
+    for output in outputs.iter() {
+        match output.edid {
+            Some(ref edid) => match edid.monitor_name {
+                Some(ref name) => info!(["OUTPUT" => output.name], "{}: edid:{} ({})", output.name, edid, name),
+                None => info!(["OUTPUT" => output.name], "{}: edid:{}", output.name, edid),
+            },
+            None => info!(["OUTPUT" => output.name], "{}: no EDID", output.name),
+        }
+    }
+}
+
+// Runs a command received on the control socket or through D-Bus; returns the text of the reply
+fn run_command(command: &ControlCommand, input: &XcbInput, outputs: &[XcbOutputDescription],
+               options: &CliOptions, config: &mut Config, state: &mut MappingState, focus_tracker: Option<&FocusTracker>) -> Result<String, String> {
+    info!("Got command {:?}", command);
+    match *command {
+        ControlCommand::SetTarget(ref target) => {
+            state.target_override = Some(target.clone());
+            update_wacom_tablets(input, outputs, &config.rules, state);
+            Ok(String::new())
+        },
+        ControlCommand::Cycle => {
+            let next_target = next_cycle_target(outputs, state.target_override.as_ref().or(config.rules.first().map(|r| &r.target)));
+            info!("Cycling to {}", next_target);
+            let reply = next_target.to_string();
+            state.target_override = Some(next_target);
+            update_wacom_tablets(input, outputs, &config.rules, state);
+            Ok(reply)
+        },
+        ControlCommand::Status => Ok(control_status(outputs, state)),
+        ControlCommand::Reapply => {
+            update_wacom_tablets(input, outputs, &config.rules, state);
+            Ok(String::new())
+        },
+        ControlCommand::Reset => {
+            state.target_override = focus_tracker.and_then(|tracker| window_target(outputs, tracker.rect(), config.clip_to_output));
+            update_wacom_tablets(input, outputs, &config.rules, state);
+            Ok(String::new())
+        },
+        ControlCommand::Reload => if reload_configuration(options, config) {
+            update_wacom_tablets(input, outputs, &config.rules, state);
+            Ok(String::new())
+        } else {
+            Err("failed to load the configuration, see the log of the watcher".to_owned())
+        },
+    }
+}
+
+// Reply to the `status` control command
+fn control_status(outputs: &[XcbOutputDescription], state: &MappingState) -> String {
+    let mut lines = vec!(format!("outputs: {}", output_names(outputs)));
+    lines.push(format!("override: {}", match state.target_override {
+        Some(ref target) => target.to_string(),
+        None => "none".to_owned(),
+    }));
+    for &(device_id, ref name, _) in state.original_matrices.iter() {
+        lines.push(format!("mapped: {} (id {})", name, device_id));
+    }
+    for device_id in state.disabled_devices.iter() {
+        lines.push(format!("disabled: device {}", device_id));
+    }
+    lines.join("\n")
+}
+
+// State published by the D-Bus service
+fn mapping_snapshot(outputs: &[XcbOutputDescription], rules: &[Rule], state: &MappingState) -> MappingSnapshot {
+    MappingSnapshot {
+        target: state.target_override.as_ref().or(rules.first().map(|r| &r.target)).map_or(String::new(), |t| t.to_string()),
+        outputs: outputs.iter().map(|o| o.name.clone()).collect(),
+        devices: state.original_matrices.iter().map(|&(_, ref name, _)| name.clone()).collect(),
+        geometry: outputs.iter().map(|o| OutputGeometry {
+            name: o.name.clone(),
+            x: o.x,
+            y: o.y,
+            width: o.width,
+            height: o.height,
+            orientation: o.orientation,
+            primary: o.primary,
+        }).collect(),
+    }
+}
+
+// Rounded up, so that waiting for the result does not return before `duration` has passed
+fn millis_ceil(duration: Duration) -> i32 {
+    (duration.as_secs() * 1000 + (duration.subsec_nanos() as u64 + 999999) / 1000000) as i32
+}
+
+// Ids and names of all XInput devices, to tell which ones a hierarchy change added or removed
+fn device_names(input: &XcbInput) -> Vec<(ffi::xcb_input_device_id_t, String)> {
+    input.get_devices().unwrap().devices.iter().map(|d| (d.deviceid, d.name.clone())).collect()
+}
+
+// Updates `known_devices` from the changes of hierarchy events, logs which devices came and
+// went, and returns the devices that were added or enabled and still exist
+fn apply_hierarchy_changes<I>(input: &XcbInput, changes: I, known_devices: &mut Vec<(ffi::xcb_input_device_id_t, String)>,
+                              state: &mut MappingState) -> Vec<XcbInputDevice>
+    where I: Iterator<Item=XcbInputHierarchyChange> {
+    let mut changed_ids: Vec<ffi::xcb_input_device_id_t> = vec!();
+    let mut added = vec!();
+    let mut removed = vec!();
+    for change in changes {
+        if change.removed() {
+            // Ids are reused, so nothing that refers to the old device may be kept
+            changed_ids.retain(|id| *id != change.deviceid);
+            added.retain(|&(id, _)| id != change.deviceid);
+            state.original_matrices.retain(|&(id, _, _)| id != change.deviceid);
+            state.original_areas.retain(|&(id, _, _)| id != change.deviceid);
+            state.disabled_devices.retain(|id| *id != change.deviceid);
+            state.retries.retain(|r| r.device_id != change.deviceid);
+            if let Some(idx) = known_devices.iter().position(|&(id, _)| id == change.deviceid) {
+                let (id, name) = known_devices.remove(idx);
+                info!(["DEVICE_ID" => id, "DEVICE" => name], "Device {} has been removed", name);
+                removed.push((id, name));
             }
+        } else if (change.added() || change.device_enabled()) && !changed_ids.contains(&change.deviceid) {
+            changed_ids.push(change.deviceid);
+        }
+    }
+    
+    let mut devices = vec!();
+    for device_id in changed_ids.into_iter() {
+        // The device may already be gone again if its removal has not been received yet
+        let device = match input.get_device(device_id).unwrap() {
+            Some(device) => device,
+            None => continue,
+        };
+        if known_devices.iter().any(|&(id, _)| id == device.deviceid) {
+            info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Device {} has been enabled", device.name);
+        } else {
+            info!(["DEVICE_ID" => device.deviceid, "DEVICE" => device.name], "Device {} has been added", device.name);
+            known_devices.push((device.deviceid, device.name.clone()));
+            added.push((device.deviceid, device.name.clone()));
+        }
+        devices.push(device);
+    }
+    if !added.is_empty() || !removed.is_empty() {
+        if let Some(ref mut events) = state.events {
+            events.hierarchy_change(&added, &removed);
+        }
+    }
+    devices
+}
+
+// Negotiates the versions of RANDR and XInput; exits when either is missing or too old
+fn init_extensions<'a>(c: &'a XcbConnection) -> (XcbRandr<'a>, XcbInput<'a>) {
+    let randr = match XcbRandr::init(c) {
+        Ok(randr) => randr,
+        Err(XcbError::LogicError(message)) => {
+            error!("{}", message);
+            process::exit(1);
+        },
+        Err(e) => {
+            error!("Failed to initialize RANDR: {:?}", e);
+            process::exit(1);
+        }
+    };
+    let input = match XcbInput::init(c) {
+        Ok(input) => input,
+        Err(XcbError::LogicError(message)) => {
+            error!("{}", message);
+            process::exit(1);
+        },
+        Err(e) => {
+            error!("Failed to initialize XInput: {:?}", e);
+            process::exit(1);
+        }
+    };
+    debug!("Using RANDR {}.{} and XInput 2.2", randr.version.0, randr.version.1);
+    (randr, input)
+}
+
+// Writes back the matrices saved by update_wacom_tablets and re-enables disabled devices
+fn restore_wacom_tablets(input: &XcbInput, state: &mut MappingState, restore_matrices: bool) {
+    if restore_matrices {
+        for &(device_id, ref name, ref matrix) in state.original_matrices.iter() {
+            info!(["DEVICE_ID" => device_id, "DEVICE" => name, "MATRIX" => format!("{:?}", matrix)],
+                  "Restoring {}: Coordinate Transformation Matrix = {:?}", name, matrix);
+            // The device may have been unplugged in the meantime
+            if let Err(e) = input.set_property_value(device_id, state.matrix_atom, state.float_atom, 32, matrix) {
+                warn!(["DEVICE_ID" => device_id, "DEVICE" => name], "Failed to restore {}: {:?}", name, e);
+            }
+        }
+        for &(device_id, ref name, ref area) in state.original_areas.iter() {
+            info!(["DEVICE_ID" => device_id, "DEVICE" => name], "Restoring {}: Wacom Tablet Area = {:?}", name, area);
+            if let Err(e) = input.set_property_value(device_id, state.area_atom, ffi::XCB_ATOM_INTEGER, 32, area) {
+                warn!(["DEVICE_ID" => device_id, "DEVICE" => name], "Failed to restore the tablet area of {}: {:?}", name, e);
+            }
+        }
+    }
+    for device_id in state.disabled_devices.drain(..) {
+        info!(["DEVICE_ID" => device_id], "Re-enabling device {}", device_id);
+        if let Err(e) = input.set_device_enabled(device_id, true) {
+            warn!(["DEVICE_ID" => device_id], "Failed to re-enable device {}: {:?}", device_id, e);
         }
     }
 }
@@ -145,57 +951,480 @@ fn main() {
     }
     
     let options = options.unwrap();
+    
+    let log_target = match options.log {
+        Some(ref target) => match logging::Target::parse(target) {
+            Some(log_target) => log_target,
+            None => {
+                error!("Invalid --log target `{}`: expected stderr, syslog or journald", target);
+                process::exit(1);
+            }
+        },
+        None => logging::Target::Stderr,
+    };
+    let log_level = logging::Level::from_verbosity(options.verbose, options.quiet);
+    if let Err(e) = logging::init(log_level, log_target) {
+        logging::init(log_level, logging::Target::Stderr).unwrap();
+        warn!("Failed to connect to {:?}, logging to stderr: {}", log_target, e);
+    }
+    
+    if options.command.as_ref().map_or(false, |command| command == "ctl") {
+        match control::send_command(&options.command_args) {
+            Ok(ref body) if body.is_empty() => {},
+            Ok(body) => println!("{}", body),
+            Err(message) => {
+                error!("{}", message);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    
+    if options.command.is_some() {
+        let c = XcbConnection::new_default();
+        let root_window_id = c.get_setup().roots_iterator().nth(0).unwrap().root;
+        let (randr, input) = init_extensions(&c);
+        let listing = list::collect_listing(&randr, &input, root_window_id).unwrap();
+        if options.json {
+            list::print_listing_json(&listing);
+        } else {
+            list::print_listing(&listing);
+        }
+        return;
+    }
+    
+    let mut config = match load_configuration(&options) {
+        Some(config) => config,
+        None => process::exit(1),
+    };
+    
+    let events = match options.events_json {
+        Some(ref path) => match EventLog::open(path) {
+            Ok(events) => Some(events),
+            Err(e) => {
+                error!("Failed to open {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     let c = XcbConnection::new_default();
     let setup = c.get_setup();
     let root_window_id = setup.roots_iterator().nth(0).unwrap().root;
-    let randr = XcbRandr::init(&c).unwrap();
+    let (randr, input) = init_extensions(&c);
     
-    let resources = randr.get_screen_resources(root_window_id).unwrap();
+    let mut resources = randr.get_screen_resources(root_window_id).unwrap();
     
-    let active_outputs: Vec<_> = get_active_outputs(&randr, &resources)
-        .iter()
-        .map(describe_output_and_crtc)
-        .collect();
-    println!("Active outputs: {:?}", active_outputs);
+    let active_outputs = describe_active_outputs(&randr, &resources, root_window_id);
+    info!("Active outputs: {}", output_names(&active_outputs));
+    debug!("Active outputs: {:?}", active_outputs);
+    log_output_identities(&active_outputs);
     
-    let input = XcbInput::init(&c).unwrap();
+    if options.show {
+        show_wacom_tablets(&c, &input, &config.rules);
+        return;
+    }
     
-    update_wacom_tablets(&c, &input, &active_outputs, &options.output);
+    // Taken before the first update, so that a replaced instance restores its devices
+    // before the original matrices are recorded again
+    let instance_lock = if config.watch && !options.dry_run {
+        match InstanceLock::acquire(&c, root_window_id, options.replace) {
+            Ok(lock) => Some(lock),
+            Err(XcbError::LogicError(message)) => {
+                error!("{}", message);
+                process::exit(1);
+            },
+            Err(e) => {
+                error!("Failed to check for a running instance: {:?}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
     
-    if options.watch {
+    let mut state = MappingState {
+        disabled_devices: vec!(),
+        target_override: None,
+        dry_run: options.dry_run,
+        original_matrices: vec!(),
+        original_areas: vec!(),
+        matrix_atom: c.intern_atom("Coordinate Transformation Matrix", true).unwrap(),
+        float_atom: c.intern_atom("FLOAT", true).unwrap(),
+        area_atom: c.intern_atom("Wacom Tablet Area", true).unwrap(),
+        retries: vec!(),
+        events: events,
+    };
+    update_wacom_tablets(&input, &active_outputs, &config.rules, &mut state);
+    
+    if config.watch {
+        signals::install_termination_handlers();
+        // Rules given on the command line take precedence over the file, which is then unused
+        let config_path = match config_file_path(&options) {
+            Some(path) if options.output.is_none() && options.area.is_none() => Some(path),
+            _ => None,
+        };
+        let mut config_watcher = match config_path {
+            Some(ref path) => {
+                signals::install_reload_handler();
+                match ConfigWatcher::new(path) {
+                    Ok(watcher) => Some(watcher),
+                    Err(e) => {
+                        warn!("Failed to watch {} for changes: {}", path.display(), e);
+                        None
+                    }
+                }
+            },
+            None => None,
+        };
+        // Like the instance lock, the control socket and the bus name are left to the watcher
+        // that actually maps the devices
+        let mut control_server = match control::socket_path() {
+            _ if options.dry_run => None,
+            Some(path) => match ControlServer::bind(path.clone()) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!("Failed to create the control socket {}: {}", path.display(), e);
+                    None
+                }
+            },
+            None => {
+                warn!("XDG_RUNTIME_DIR is not set, the control socket is disabled");
+                None
+            }
+        };
         randr.select_input(root_window_id).unwrap();
         input.select_device_add_remove_events(root_window_id).unwrap();
         
         let mut prev_outputs = active_outputs;
+        let mut known_devices = device_names(&input);
+        let mut mapper_service = if options.dry_run {
+            None
+        } else {
+            match MapperService::start(mapping_snapshot(&prev_outputs, &config.rules, &state)) {
+                Ok(service) => Some(service),
+                Err(e) => {
+                    warn!("Failed to register {} on the session bus: {}", dbus_service::BUS_NAME, e);
+                    None
+                }
+            }
+        };
+        
+        let mut pointer_follower = match config.follow {
+            Some(FollowMode::Pointer) => Some(PointerFollower::new(config.dwell_ms, config.hysteresis)),
+            _ => None,
+        };
+        let mut focus_tracker = match config.follow {
+            Some(FollowMode::Window) => match FocusTracker::new(&c, root_window_id) {
+                Ok(tracker) => Some(tracker),
+                Err(e) => {
+                    warn!("Failed to track the active window: {:?}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        if let Some(ref mut tracker) = focus_tracker {
+            if tracker.update_window(&c) {
+                state.target_override = window_target(&prev_outputs, tracker.rect(), config.clip_to_output);
+                update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+            }
+        }
+        let cycle_grab = match config.cycle_key {
+            Some(ref hotkey) => match hotkey.grab(&c, root_window_id) {
+                Ok(grab) => Some(grab),
+                Err(e) => {
+                    warn!("Failed to grab {}: {:?}", hotkey.spec, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        
+        let poll_timeout = if pointer_follower.is_some() { POINTER_POLL_INTERVAL_MS } else { -1 };
+        let mut last_pointer_check = Instant::now();
+        // RANDR and hierarchy events are only noted; outputs and devices are evaluated once
+        // no such event has arrived for config.debounce_ms, or when the burst has gone on
+        // for MAX_DEBOUNCE_INTERVALS times as long
+        let debounce = Duration::from_millis(config.debounce_ms);
+        let max_latency = debounce * MAX_DEBOUNCE_INTERVALS;
+        // First and last event of the burst that has not been evaluated yet
+        let mut change_burst: Option<(Instant, Instant)> = None;
+        let mut outputs_changed = false;
+        // Changes of all hierarchy events received since the last evaluation, in order
+        let mut hierarchy_changes: Vec<XcbInputHierarchyChange> = vec!();
         
         loop {
-            let event = c.wait_for_event().unwrap();
+            signals::clear_wake_up();
+            if let Some(signum) = signals::received_signal() {
+                info!("Got signal {}, exiting", signum);
+                restore_wacom_tablets(&input, &mut state, config.restore);
+                break;
+            }
+            
+            if signals::take_reload_request() {
+                info!("Got SIGHUP, reloading the configuration");
+                if reload_configuration(&options, &mut config) {
+                    update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+                }
+            }
+            
+            let burst_due = change_burst.map(|(first, last)| cmp::min(last + debounce, first + max_latency));
+            let now = Instant::now();
+            let debounce_remaining = match burst_due {
+                Some(due) if now < due => Some(due - now),
+                Some(_) => {
+                    change_burst = None;
+                    let mut needs_update = false;
+                    if outputs_changed {
+                        // Outputs and CRTCs may have been added, e.g. by a DisplayLink dock
+                        match randr.get_screen_resources(root_window_id) {
+                            Ok(new_resources) => resources = new_resources,
+                            Err(e) => warn!("Failed to get the screen resources: {:?}", e),
+                        }
+                        let active_outputs = describe_active_outputs(&randr, &resources, root_window_id);
+                        if active_outputs != prev_outputs {
+                            info!("Active outputs have changed from {} to {}", output_names(&prev_outputs), output_names(&active_outputs));
+                            debug!("Active outputs have changed from {:?} to {:?}", prev_outputs, active_outputs);
+                            log_output_identities(&active_outputs);
+                            if let Some(ref mut events) = state.events {
+                                events.layout_change(&prev_outputs, &active_outputs);
+                            }
+                            if let Some(ref tracker) = focus_tracker {
+                                state.target_override = window_target(&active_outputs, tracker.rect(), config.clip_to_output);
+                            }
+                            prev_outputs = active_outputs;
+                            needs_update = true;
+                        }
+                    }
+                    let changed_devices = apply_hierarchy_changes(&input, hierarchy_changes.drain(..), &mut known_devices, &mut state);
+                    outputs_changed = false;
+                    if needs_update {
+                        // All devices, still retrying the matrix of those that have just been added
+                        match input.get_devices() {
+                            Ok(devices) => for device in devices.devices.iter() {
+                                let just_added = changed_devices.iter().any(|d| d.deviceid == device.deviceid);
+                                update_wacom_tablet(&input, &prev_outputs, &config.rules, &mut state, device, just_added);
+                            },
+                            Err(e) => warn!("Failed to list the input devices: {:?}", e),
+                        }
+                    } else {
+                        for device in changed_devices.iter() {
+                            update_wacom_tablet(&input, &prev_outputs, &config.rules, &mut state, device, true);
+                        }
+                    }
+                    None
+                },
+                None => None,
+            };
+            
+            let retry_remaining = run_matrix_retries(&input, &prev_outputs, &config.rules, &mut state);
+            
+            if let Some(ref mut service) = mapper_service {
+                if let Err(e) = service.publish(mapping_snapshot(&prev_outputs, &config.rules, &state)) {
+                    warn!("Failed to emit D-Bus signals: {}", e);
+                }
+            }
+            
+            let mut fds = control_server.as_ref().map_or(vec!(), |server| server.fds());
+            fds.extend(mapper_service.as_ref().map(|service| service.fd()));
+            fds.extend(config_watcher.as_ref().map(|watcher| watcher.fd()));
+            fds.extend(signals::wake_up_fd());
+            let client_remaining = control_server.as_ref().and_then(|server| server.next_timeout());
+            let wake_up = [debounce_remaining, retry_remaining, client_remaining].iter().filter_map(|r| *r).min();
+            let timeout = match wake_up {
+                Some(remaining) if poll_timeout >= 0 => cmp::min(poll_timeout, millis_ceil(remaining)),
+                Some(remaining) => millis_ceil(remaining),
+                None => poll_timeout,
+            };
+            let event_opt = match c.wait_for_event_or_fds(timeout, &fds) {
+                Ok(event_opt) => event_opt,
+                Err(_) => {
+                    // Nothing can be restored without a connection; the X server resets
+                    // device properties itself when it shuts down
+                    error!("Connection to the X server has been closed, exiting");
+                    break;
+                }
+            };
+            
+            if let Some(ref mut follower) = pointer_follower {
+                if event_opt.is_none() || last_pointer_check.elapsed() >= Duration::from_millis(POINTER_POLL_INTERVAL_MS as u64) {
+                    last_pointer_check = Instant::now();
+                    let (x, y) = c.query_pointer(root_window_id).unwrap();
+                    if let Some(output) = follower.update(&prev_outputs, x, y, last_pointer_check) {
+                        info!(["OUTPUT" => output], "Pointer moved to {}", output);
+                        state.target_override = Some(Target::Output(vec!(OutputSelector::Name(output))));
+                        update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+                    }
+                }
+            }
+            
+            if config_watcher.as_mut().map_or(false, |watcher| watcher.poll_changed()) {
+                info!("The configuration file has changed, reloading it");
+                if reload_configuration(&options, &mut config) {
+                    update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+                }
+            }
+            
+            if let Some(ref mut server) = control_server {
+                for request in server.poll_requests() {
+                    let result = match request.command {
+                        Ok(ref command) => run_command(command, &input, &prev_outputs, &options, &mut config, &mut state, focus_tracker.as_ref()),
+                        Err(ref message) => Err(message.clone()),
+                    };
+                    request.reply(result);
+                }
+            }
+            
+            let dbus_requests = match mapper_service {
+                Some(ref mut service) => service.poll_requests(),
+                None => Ok(vec!()),
+            };
+            match dbus_requests {
+                Ok(requests) => for request in requests.into_iter() {
+                    let result = run_command(&request.command, &input, &prev_outputs, &options, &mut config, &mut state, focus_tracker.as_ref());
+                    mapper_service.as_mut().unwrap().reply(request, result);
+                },
+                Err(e) => {
+                    warn!("Lost the connection to the session bus: {}", e);
+                    mapper_service = None;
+                }
+            }
+            
+            let event = match event_opt {
+                Some(event) => event,
+                None => continue,
+            };
+            
+            if let Some(ref lock) = instance_lock {
+                if lock.is_lost(&event) {
+                    info!("Replaced by another instance, exiting");
+                    restore_wacom_tablets(&input, &mut state, config.restore);
+                    break;
+                }
+            }
             
-            if event.response_type >= randr.extension.first_event
-                && event.response_type <= randr.extension.first_event + (ffi::XCB_RANDR_NOTIFY_RESOURCE_CHANGE as u8)
-            {
-                let event_type: XcbRandrEventType = XcbRandrEventType::from_u8(event.response_type - randr.extension.first_event).expect("Invalid value");
-                println!("Got RANDR event: {:?}", event_type);
-                let active_outputs: Vec<_> = get_active_outputs(&randr, &resources)
-                    .iter()
-                    .map(describe_output_and_crtc)
-                    .collect();
-                if active_outputs != prev_outputs {
-                    println!("Active outputs have changed from {:?} to {:?}", prev_outputs, active_outputs);
-    
-                    update_wacom_tablets(&c, &input, &active_outputs, &options.output);
-                    prev_outputs = active_outputs;
-                }
-            } else if event.response_type == 35 /* XCB_GE_GENERIC */ {
-                let ge = unsafe { &*(event.value as *const ffi::xcb_ge_generic_event_t) };
-                if ge.extension == input.extension.major_opcode {
-                    if ge.event_type == 11 /* XINPUT Hierarchy event */ {
-                        println!("Device hierarchy changed");
-                        //sleep(Duration::seconds(1));
-                        update_wacom_tablets(&c, &input, &prev_outputs, &options.output);
+            if let Some(ref mut tracker) = focus_tracker {
+                if tracker.handle_event(&c, &event) {
+                    state.target_override = window_target(&prev_outputs, tracker.rect(), config.clip_to_output);
+                    match state.target_override {
+                        Some(ref target) => info!("Active window moved to {}", target),
+                        None => info!("No window is active"),
                     }
+                    update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+                    continue;
+                }
+            }
+            
+            if let Some(randr_event) = randr.decode_event(&event) {
+                debug!("Got RANDR event: {:?}", randr_event);
+                if let Some(ref mut events) = state.events {
+                    events.randr_change(&randr_event);
                 }
+                outputs_changed = true;
+                let now = Instant::now();
+                change_burst = Some((change_burst.map_or(now, |(first, _)| first), now));
+            } else if event.response_type & 0x7f == 2 /* XCB_KEY_PRESS */ {
+                let key_press = unsafe { &*(event.value as *const ffi::xcb_key_press_event_t) };
+                if let Some(ref grab) = cycle_grab {
+                    if grab.matches(key_press) {
+                        let next_target = next_cycle_target(&prev_outputs, state.target_override.as_ref().or(config.rules.first().map(|r| &r.target)));
+                        info!("Cycling to {}", next_target);
+                        state.target_override = Some(next_target);
+                        update_wacom_tablets(&input, &prev_outputs, &config.rules, &mut state);
+                    }
+                }
+            } else if let Some(changes) = input.decode_hierarchy_event(&event) {
+                debug!("Device hierarchy changed: {:?}", changes);
+                hierarchy_changes.extend(changes);
+                let now = Instant::now();
+                change_burst = Some((change_burst.map_or(now, |(first, _)| first), now));
+            }
+        }
+    } else {
+        // Without the event loop, the retries have to be waited for here
+        while let Some(delay) = run_matrix_retries(&input, &active_outputs, &config.rules, &mut state) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(name: &str, x: i16) -> XcbOutputDescription {
+        XcbOutputDescription {
+            name: name.to_owned(),
+            x: x,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            orientation: XcbRandrOrientation::from_u16(1),
+            edid: None,
+            primary: false,
+            mm_width: 0,
+            mm_height: 0,
+        }
+    }
+
+    fn stylus() -> XcbInputDevice {
+        XcbInputDevice { deviceid: 10, _type: 0, attachment: 2, enabled: true, name: "Wacom Pen stylus".to_owned(), valuators: vec!() }
+    }
+
+    fn rule(output: &str, on_missing: MissingPolicy) -> Rule {
+        Rule {
+            matcher: DeviceMatcher::parse(&[], &[]).unwrap(),
+            target: Target::Output(vec!(OutputSelector::Name(output.to_owned()))),
+            aspect: AspectMode::Stretch,
+            on_missing: on_missing,
+        }
+    }
+
+    fn mapping(outputs: &[XcbOutputDescription], rule: &Rule) -> MappingAction {
+        compute_mapping(outputs, rule, &rule.target, &stylus(), false)
+    }
+
+    fn applied(action: MappingAction) -> (Vec<f32>, Option<String>) {
+        match action {
+            MappingAction::Apply(matrix, output, _) => (matrix, output),
+            MappingAction::Keep => panic!("expected a matrix, got Keep"),
+            MappingAction::Disable => panic!("expected a matrix, got Disable"),
+        }
+    }
+
+    #[test]
+    fn active_output_is_used_whatever_the_policy() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        for policy in [MissingPolicy::Next, MissingPolicy::Span, MissingPolicy::Keep, MissingPolicy::Disable].iter() {
+            let (matrix, name) = applied(mapping(&outputs, &rule("DP-1", *policy)));
+            assert_eq!(name, Some("DP-1".to_owned()));
+            assert_eq!(matrix, vec!(0.5, 0.0, 0.5, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn missing_output_follows_the_policy() {
+        let outputs = vec!(output("eDP-1", 0), output("DP-1", 1920));
+        assert_eq!(applied(mapping(&outputs, &rule("HDMI-1", MissingPolicy::Next))), applied(mapping(&outputs, &rule("eDP-1", MissingPolicy::Next))));
+        assert_eq!(applied(mapping(&outputs, &rule("HDMI-1", MissingPolicy::Span))), (identity_matrix(), None));
+        match mapping(&outputs, &rule("HDMI-1", MissingPolicy::Keep)) {
+            MappingAction::Keep => {},
+            _ => panic!("expected Keep"),
+        }
+        match mapping(&outputs, &rule("HDMI-1", MissingPolicy::Disable)) {
+            MappingAction::Disable => {},
+            _ => panic!("expected Disable"),
+        }
+    }
+
+    #[test]
+    fn nothing_is_mapped_without_outputs() {
+        for policy in [MissingPolicy::Next, MissingPolicy::Span, MissingPolicy::Keep, MissingPolicy::Disable].iter() {
+            match mapping(&[], &rule("DP-1", *policy)) {
+                MappingAction::Keep => {},
+                _ => panic!("expected Keep"),
             }
         }
     }
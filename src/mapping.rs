@@ -0,0 +1,491 @@
+use xcb::*;
+use outputs::OutputSelector;
+use std::fmt;
+use std::result::Result;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    pub fn bounding_box(rects: &[Rect]) -> Option<Rect> {
+        if rects.is_empty() {
+            return None;
+        }
+        let min_x = rects.iter().map(|r| r.x).fold(rects[0].x, f32::min);
+        let min_y = rects.iter().map(|r| r.y).fold(rects[0].y, f32::min);
+        let max_x = rects.iter().map(|r| r.x + r.width).fold(rects[0].x + rects[0].width, f32::max);
+        let max_y = rects.iter().map(|r| r.y + r.height).fold(rects[0].y + rects[0].height, f32::max);
+        Some(Rect { x: min_x, y: min_y, width: max_x - min_x, height: max_y - min_y })
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let width = (self.x + self.width).min(other.x + other.width) - x;
+        let height = (self.y + self.height).min(other.y + other.height) - y;
+        if width <= 0.0 || height <= 0.0 {
+            None
+        } else {
+            Some(Rect { x: x, y: y, width: width, height: height })
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Length {
+    Pixels(f32),
+    Percent(f32),
+}
+
+impl Length {
+    fn resolve(&self, reference: f32) -> f32 {
+        match *self {
+            Length::Pixels(v) => v,
+            Length::Percent(p) => p * reference / 100.0,
+        }
+    }
+
+    fn is_positive(&self) -> bool {
+        match *self {
+            Length::Pixels(v) | Length::Percent(v) => v > 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Length::Pixels(v) => write!(fmt, "{}", v),
+            Length::Percent(p) => write!(fmt, "{}%", p),
+        }
+    }
+}
+
+// Screen area in X geometry syntax: [OUTPUT:]WxH[+X+Y], where each component is either
+// in pixels or in percents of the output (or of the whole screen when OUTPUT is omitted)
+#[derive(Debug,Clone,PartialEq)]
+pub struct Geometry {
+    pub output: Option<OutputSelector>,
+    pub width: Length,
+    pub height: Length,
+    pub x: Length,
+    pub y: Length,
+}
+
+impl Geometry {
+    pub fn parse(s: &str) -> Result<Geometry, String> {
+        let (output, spec) = match s.rfind(':') {
+            Some(idx) => (Some(try!(OutputSelector::parse(&s[..idx]))), &s[idx + 1..]),
+            None => (None, s),
+        };
+        let invalid = || format!("Invalid area `{}`: expected [OUTPUT:]WxH[+X+Y]", s);
+
+        let size_end = spec.find(|c| c == '+' || c == '-').unwrap_or(spec.len());
+        let size: Vec<_> = spec[..size_end].split('x').collect();
+        if size.len() != 2 {
+            return Err(invalid());
+        }
+        let width = try!(parse_length(size[0]).ok_or_else(&invalid));
+        let height = try!(parse_length(size[1]).ok_or_else(&invalid));
+        if !width.is_positive() || !height.is_positive() {
+            return Err(format!("Invalid area `{}`: width and height must be greater than zero", s));
+        }
+
+        let mut offsets = vec!();
+        let mut rest = &spec[size_end..];
+        while !rest.is_empty() {
+            let end = rest[1..].find(|c| c == '+' || c == '-').map(|idx| idx + 1).unwrap_or(rest.len());
+            let value = if rest.starts_with('+') { &rest[1..end] } else { &rest[..end] };
+            offsets.push(try!(parse_length(value).ok_or_else(&invalid)));
+            rest = &rest[end..];
+        }
+        let (x, y) = match offsets.len() {
+            0 => (Length::Pixels(0.0), Length::Pixels(0.0)),
+            2 => (offsets[0], offsets[1]),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Geometry { output: output, width: width, height: height, x: x, y: y })
+    }
+
+    // Resolves the geometry against `reference`, the output rect (or the screen bounding box).
+    // Offsets in pixels are X screen coordinates when no output is given; all other offsets
+    // are relative to `reference`.
+    pub fn to_rect(&self, reference: &Rect) -> Rect {
+        let offset = |length: &Length, origin: f32, size: f32| match *length {
+            Length::Pixels(v) if self.output.is_none() => v,
+            _ => origin + length.resolve(size),
+        };
+        Rect {
+            x: offset(&self.x, reference.x, reference.width),
+            y: offset(&self.y, reference.y, reference.height),
+            width: self.width.resolve(reference.width),
+            height: self.height.resolve(reference.height),
+        }
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if let Some(ref output) = self.output {
+            try!(write!(fmt, "{}:", output));
+        }
+        let offset = |l: &Length| {
+            let s = l.to_string();
+            if s.starts_with('-') { s } else { format!("+{}", s) }
+        };
+        write!(fmt, "{}x{}{}{}", self.width, self.height, offset(&self.x), offset(&self.y))
+    }
+}
+
+fn parse_length(s: &str) -> Option<Length> {
+    let (number, percent) = if s.ends_with('%') { (&s[..s.len() - 1], true) } else { (s, false) };
+    match number.parse::<f32>() {
+        Ok(v) if !v.is_finite() => None,
+        Ok(v) if percent => Some(Length::Percent(v)),
+        Ok(v) => Some(Length::Pixels(v)),
+        Err(_) => None,
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AspectMode {
+    Stretch,
+    // Use a part of the tablet so that the whole target area is covered (needs the
+    // "Wacom Tablet Area" property of xf86-input-wacom, see crop_area)
+    Crop,
+    // Use a part of the target area so that the whole tablet is used
+    Letterbox,
+}
+
+impl AspectMode {
+    pub fn parse(s: &str) -> Option<AspectMode> {
+        match s {
+            "stretch" => Some(AspectMode::Stretch),
+            "crop" => Some(AspectMode::Crop),
+            "letterbox" => Some(AspectMode::Letterbox),
+            _ => None
+        }
+    }
+}
+
+// Physical aspect ratio of the tablet's active area, from its X and Y valuators
+pub fn tablet_aspect(device: &XcbInputDevice) -> Option<f32> {
+    match (device.get_valuator(0), device.get_valuator(1)) {
+        (Some(x), Some(y)) => physical_aspect(device, x.max - x.min, y.max - y.min),
+        _ => None,
+    }
+}
+
+// Aspect ratio of an area of `width` x `height` device units, corrected by the resolution of
+// the X and Y valuators when both are known
+fn physical_aspect(device: &XcbInputDevice, mut width: f64, mut height: f64) -> Option<f32> {
+    if let (Some(x), Some(y)) = (device.get_valuator(0), device.get_valuator(1)) {
+        if x.resolution != 0 && y.resolution != 0 {
+            width = width / x.resolution as f64;
+            height = height / y.resolution as f64;
+        }
+    }
+    if width <= 0.0 || height <= 0.0 {
+        None
+    } else {
+        Some((width / height) as f32)
+    }
+}
+
+// Largest centered part of `area` (in device units, as in the "Wacom Tablet Area" property)
+// whose physical aspect ratio is `aspect`. This is what AspectMode::Crop maps onto the target.
+pub fn crop_area(device: &XcbInputDevice, area: Rect, aspect: f32) -> Rect {
+    match physical_aspect(device, area.width as f64, area.height as f64) {
+        Some(area_aspect) if area_aspect > aspect => {
+            let width = area.width * aspect / area_aspect;
+            Rect { x: area.x + (area.width - width) / 2.0, width: width, ..area }
+        },
+        Some(area_aspect) => {
+            let height = area.height * area_aspect / aspect;
+            Rect { y: area.y + (area.height - height) / 2.0, height: height, ..area }
+        },
+        None => area,
+    }
+}
+
+// Part of `target` the tablet is mapped onto. With AspectMode::Crop, this is the whole
+// target; the tablet itself is cropped instead (see crop_area).
+pub fn fit_aspect(target: Rect, aspect: f32, mode: AspectMode) -> Rect {
+    let target_aspect = target.width / target.height;
+    let tablet_is_wider = aspect > target_aspect;
+    match mode {
+        AspectMode::Stretch | AspectMode::Crop => target,
+        AspectMode::Letterbox => if tablet_is_wider {
+            let height = target.width / aspect;
+            Rect { y: target.y + (target.height - height) / 2.0, height: height, ..target }
+        } else {
+            let width = target.height * aspect;
+            Rect { x: target.x + (target.width - width) / 2.0, width: width, ..target }
+        },
+    }
+}
+
+// Maps normalized tablet coordinates to normalized coordinates of an output with the
+// given orientation, so that the pen follows the output when it is rotated or reflected
+pub fn orientation_matrix(orientation: XcbRandrOrientation) -> Vec<f32> {
+    let rotation = match orientation.rotation {
+        XcbRandrRotation::Rotate0 => identity_matrix(),
+        // xrandr --rotate left
+        XcbRandrRotation::Rotate90 => vec!(
+            0.0, -1.0, 1.0,
+            1.0,  0.0, 0.0,
+            0.0,  0.0, 1.0
+        ),
+        XcbRandrRotation::Rotate180 => vec!(
+            -1.0,  0.0, 1.0,
+             0.0, -1.0, 1.0,
+             0.0,  0.0, 1.0
+        ),
+        // xrandr --rotate right
+        XcbRandrRotation::Rotate270 => vec!(
+             0.0, 1.0, 0.0,
+            -1.0, 0.0, 1.0,
+             0.0, 0.0, 1.0
+        ),
+    };
+    let reflection = vec!(
+        if orientation.reflect_x { -1.0 } else { 1.0 }, 0.0, if orientation.reflect_x { 1.0 } else { 0.0 },
+        0.0, if orientation.reflect_y { -1.0 } else { 1.0 }, if orientation.reflect_y { 1.0 } else { 0.0 },
+        0.0, 0.0, 1.0
+    );
+    multiply(&reflection, &rotation)
+}
+
+// Tablet aspect ratio as seen on an output with the given orientation
+pub fn oriented_aspect(aspect: f32, orientation: XcbRandrOrientation) -> f32 {
+    match orientation.rotation {
+        XcbRandrRotation::Rotate90 | XcbRandrRotation::Rotate270 => 1.0 / aspect,
+        XcbRandrRotation::Rotate0 | XcbRandrRotation::Rotate180 => aspect,
+    }
+}
+
+pub fn identity_matrix() -> Vec<f32> {
+    vec!(
+        1.0, 0.0, 0.0,
+        0.0, 1.0, 0.0,
+        0.0, 0.0, 1.0
+    )
+}
+
+pub fn multiply(a: &[f32], b: &[f32]) -> Vec<f32> {
+    let mut result = vec!(0.0; 9);
+    for row in 0..3 {
+        for col in 0..3 {
+            result[row * 3 + col] = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).fold(0.0, |acc, x| acc + x);
+        }
+    }
+    result
+}
+
+// Coordinate Transformation Matrix that maps the whole tablet onto `target`, where both
+// the matrix input and output are normalized to the screen bounding box `bounds`
+pub fn transform_matrix(target: &Rect, bounds: &Rect, orientation: XcbRandrOrientation) -> Vec<f32> {
+    let dx = (target.x - bounds.x) / bounds.width;
+    let dy = (target.y - bounds.y) / bounds.height;
+    let cx = target.width / bounds.width;
+    let cy = target.height / bounds.height;
+
+    let placement = vec!(
+         cx,  0.0,   dx,
+        0.0,   cy,   dy,
+        0.0,  0.0,  1.0
+    );
+    multiply(&placement, &orientation_matrix(orientation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xcb::*;
+    use std::borrow::ToOwned;
+
+    fn tablet(width: f64, height: f64, x_resolution: u32, y_resolution: u32) -> XcbInputDevice {
+        let valuator = |number, max, resolution| XcbInputValuator { number: number, label: 0, min: 0.0, max: max, resolution: resolution };
+        XcbInputDevice {
+            deviceid: 10,
+            _type: XcbInputDeviceType::SlavePointer as u16,
+            attachment: 2,
+            enabled: true,
+            name: "Wacom Pen stylus".to_owned(),
+            valuators: vec!(valuator(0, width, x_resolution), valuator(1, height, y_resolution)),
+        }
+    }
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect { x: x, y: y, width: width, height: height }
+    }
+
+    fn orientation(rotation: XcbRandrRotation, reflect_x: bool, reflect_y: bool) -> XcbRandrOrientation {
+        XcbRandrOrientation { rotation: rotation, reflect_x: reflect_x, reflect_y: reflect_y }
+    }
+
+    // Where the normalized tablet point (x, y) lands on the screen
+    fn apply(matrix: &[f32], x: f32, y: f32) -> (f32, f32) {
+        (matrix[0] * x + matrix[1] * y + matrix[2], matrix[3] * x + matrix[4] * y + matrix[5])
+    }
+
+    // Tablet corners (0,0) and (1,0) on a single output, as with the matrices of the xrandr man page
+    fn corners(orientation: XcbRandrOrientation) -> ((f32, f32), (f32, f32)) {
+        let screen = rect(0.0, 0.0, 1920.0, 1080.0);
+        let matrix = transform_matrix(&screen, &screen, orientation);
+        (apply(&matrix, 0.0, 0.0), apply(&matrix, 1.0, 0.0))
+    }
+
+    #[test]
+    fn rotations_follow_xrandr() {
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate0, false, false)), ((0.0, 0.0), (1.0, 0.0)));
+        // left: 0 -1 1 / 1 0 0
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate90, false, false)), ((1.0, 0.0), (1.0, 1.0)));
+        // inverted: -1 0 1 / 0 -1 1
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate180, false, false)), ((1.0, 1.0), (0.0, 1.0)));
+        // right: 0 1 0 / -1 0 1
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate270, false, false)), ((0.0, 1.0), (0.0, 0.0)));
+    }
+
+    #[test]
+    fn reflections_follow_xrandr() {
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate0, true, false)), ((1.0, 0.0), (0.0, 0.0)));
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate0, false, true)), ((0.0, 1.0), (1.0, 1.0)));
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate0, true, true)), ((1.0, 1.0), (0.0, 1.0)));
+    }
+
+    // The X server reflects after rotating (RRTransformCompute), so the order of the product matters
+    #[test]
+    fn reflections_compose_with_rotations() {
+        // --rotate left --reflect x: 0 1 0 / 1 0 0
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate90, true, false)), ((0.0, 0.0), (0.0, 1.0)));
+        // --rotate right --reflect y: 0 1 0 / 1 0 0 as well
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate270, false, true)), ((0.0, 0.0), (0.0, 1.0)));
+        // --rotate left --reflect y: 0 -1 1 / -1 0 1
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate90, false, true)), ((1.0, 1.0), (1.0, 0.0)));
+        // --rotate right --reflect x: 0 -1 1 / -1 0 1
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate270, true, false)), ((1.0, 1.0), (1.0, 0.0)));
+        // --rotate inverted --reflect x: 1 0 0 / 0 -1 1
+        assert_eq!(corners(orientation(XcbRandrRotation::Rotate180, true, false)), ((0.0, 1.0), (1.0, 1.0)));
+    }
+
+    #[test]
+    fn rotated_output_next_to_another() {
+        // DP-1 rotated left, to the right of a 1920x1080 output
+        let bounds = rect(0.0, 0.0, 3000.0, 1920.0);
+        let output = rect(1920.0, 0.0, 1080.0, 1920.0);
+        let matrix = transform_matrix(&output, &bounds, orientation(XcbRandrRotation::Rotate90, false, false));
+        assert_eq!(apply(&matrix, 0.0, 0.0), (1.0, 0.0));
+        assert_eq!(apply(&matrix, 1.0, 0.0), (1.0, 1.0));
+        assert_eq!(apply(&matrix, 0.0, 1.0), (0.64, 0.0));
+    }
+
+    #[test]
+    fn parses_areas() {
+        assert_eq!(Geometry::parse("1280x1440+0+0"), Ok(Geometry {
+            output: None,
+            width: Length::Pixels(1280.0),
+            height: Length::Pixels(1440.0),
+            x: Length::Pixels(0.0),
+            y: Length::Pixels(0.0),
+        }));
+        assert_eq!(Geometry::parse("DP-1:50%x100%-10+25%"), Ok(Geometry {
+            output: Some(OutputSelector::Name("DP-1".to_owned())),
+            width: Length::Percent(50.0),
+            height: Length::Percent(100.0),
+            x: Length::Pixels(-10.0),
+            y: Length::Percent(25.0),
+        }));
+        assert_eq!(Geometry::parse("800x600").unwrap().x, Length::Pixels(0.0));
+    }
+
+    #[test]
+    fn rejects_invalid_areas() {
+        for spec in ["", "800", "800x", "x600", "800x600+10", "800x600+1+2+3", "axb", "DP-1:800x600+a+0"].iter() {
+            assert!(Geometry::parse(spec).is_err(), "`{}` was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_and_non_finite_areas() {
+        for spec in ["0x600", "800x0", "0%x100%", "800x-600", "inf%x100%", "800xNaN", "800x600+inf+0"].iter() {
+            assert!(Geometry::parse(spec).is_err(), "`{}` was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn area_display_round_trips() {
+        for spec in ["1280x1440+0+0", "DP-1:50%x100%+0+0", "HDMI-1:640x480-10+20", "33.5%x50%+10%-5%"].iter() {
+            let geometry = Geometry::parse(spec).unwrap();
+            assert_eq!(geometry.to_string(), *spec);
+            assert_eq!(Geometry::parse(&geometry.to_string()), Ok(geometry));
+        }
+    }
+
+    #[test]
+    fn area_offsets_without_output_are_screen_coordinates() {
+        // Outputs that do not start at the origin of the screen
+        let bounds = rect(100.0, 50.0, 2000.0, 1000.0);
+        assert_eq!(Geometry::parse("800x600+200+100").unwrap().to_rect(&bounds), rect(200.0, 100.0, 800.0, 600.0));
+        assert_eq!(Geometry::parse("50%x100%+50%+0%").unwrap().to_rect(&bounds), rect(1100.0, 50.0, 1000.0, 1000.0));
+        let output = rect(1920.0, 0.0, 1920.0, 1080.0);
+        assert_eq!(Geometry::parse("DP-1:50%x100%+10+0").unwrap().to_rect(&output), rect(1930.0, 0.0, 960.0, 1080.0));
+    }
+
+    #[test]
+    fn tablet_aspect_uses_the_resolution() {
+        assert_eq!(tablet_aspect(&tablet(32000.0, 20000.0, 200000, 200000)), Some(1.6));
+        assert_eq!(tablet_aspect(&tablet(32000.0, 20000.0, 200000, 100000)), Some(0.8));
+    }
+
+    #[test]
+    fn tablet_aspect_ignores_a_zero_resolution() {
+        assert_eq!(tablet_aspect(&tablet(32000.0, 20000.0, 0, 100000)), Some(1.6));
+        assert_eq!(tablet_aspect(&tablet(32000.0, 0.0, 0, 0)), None);
+        let mut device = tablet(32000.0, 20000.0, 0, 0);
+        device.valuators.pop();
+        assert_eq!(tablet_aspect(&device), None);
+    }
+
+    #[test]
+    fn letterbox_stays_inside_the_target() {
+        let target = rect(1920.0, 0.0, 1920.0, 1080.0);
+        assert_eq!(fit_aspect(target, 2.0, AspectMode::Letterbox), rect(1920.0, 60.0, 1920.0, 960.0));
+        assert_eq!(fit_aspect(target, 1.5, AspectMode::Letterbox), rect(2070.0, 0.0, 1620.0, 1080.0));
+    }
+
+    #[test]
+    fn stretch_and_crop_cover_exactly_the_target() {
+        let target = rect(1920.0, 0.0, 1920.0, 1080.0);
+        assert_eq!(fit_aspect(target, 1.5, AspectMode::Stretch), target);
+        assert_eq!(fit_aspect(target, 1.5, AspectMode::Crop), target);
+    }
+
+    #[test]
+    fn crop_area_is_centered_in_the_tablet() {
+        let device = tablet(32000.0, 20000.0, 0, 0);
+        let area = rect(0.0, 0.0, 32000.0, 20000.0);
+        // 16:10 tablet on a 16:9 output: the top and bottom of the tablet are unused
+        let cropped = crop_area(&device, area, 16.0 / 9.0);
+        assert_eq!((cropped.x, cropped.width), (0.0, 32000.0));
+        assert!((cropped.height - 18000.0).abs() < 0.5 && (cropped.y - 1000.0).abs() < 0.5);
+        // on a 4:3 output: the left and right
+        let cropped = crop_area(&device, area, 4.0 / 3.0);
+        assert_eq!((cropped.y, cropped.height), (0.0, 20000.0));
+        assert!((cropped.width - 26666.67).abs() < 0.5 && (cropped.x - 2666.67).abs() < 0.5);
+    }
+
+    #[test]
+    fn crop_area_without_valuators_is_the_whole_area() {
+        let mut device = tablet(0.0, 0.0, 0, 0);
+        device.valuators.clear();
+        let area = rect(0.0, 0.0, 0.0, 20000.0);
+        assert_eq!(crop_area(&device, area, 1.0), area);
+    }
+}
@@ -0,0 +1,196 @@
+use xcb::*;
+use regex::Regex;
+use std::result::Result;
+
+#[derive(Debug,Clone)]
+pub enum DeviceCriterion {
+    Name(Regex),
+    ProductId(u32, u32),
+    Type(DeviceTypeFilter),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DeviceTypeFilter {
+    Master,
+    Slave,
+    Exact(XcbInputDeviceType),
+}
+
+#[derive(Debug,Clone)]
+pub struct DeviceMatcher {
+    pub include: Vec<DeviceCriterion>,
+    pub exclude: Vec<DeviceCriterion>,
+}
+
+const DEFAULT_INCLUDE: &'static str = "name:^Wacom";
+
+impl DeviceCriterion {
+    // SPEC is one of name:REGEX, id:VENDOR:PRODUCT (hex) or type:TYPE
+    pub fn parse(spec: &str) -> Result<DeviceCriterion, String> {
+        let (kind, value) = match spec.find(':') {
+            Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+            None => return Err(format!("Invalid device criterion `{}`: expected name:REGEX, id:VENDOR:PRODUCT or type:TYPE", spec)),
+        };
+        match kind {
+            "name" => match Regex::new(value) {
+                Ok(re) => Ok(DeviceCriterion::Name(re)),
+                Err(e) => Err(format!("Invalid device name regex `{}`: {}", value, e)),
+            },
+            "id" => {
+                let parts: Vec<_> = value.split(':').collect();
+                if parts.len() != 2 {
+                    return Err(format!("Invalid device product id `{}`: expected VENDOR:PRODUCT", value));
+                }
+                match (u32::from_str_radix(parts[0], 16), u32::from_str_radix(parts[1], 16)) {
+                    (Ok(vendor), Ok(product)) => Ok(DeviceCriterion::ProductId(vendor, product)),
+                    _ => Err(format!("Invalid device product id `{}`: vendor and product must be hexadecimal", value)),
+                }
+            },
+            "type" => match DeviceTypeFilter::parse(value) {
+                Some(filter) => Ok(DeviceCriterion::Type(filter)),
+                None => Err(format!("Invalid device type `{}`: expected one of master, master-pointer, master-keyboard, slave, slave-pointer, slave-keyboard, floating", value)),
+            },
+            _ => Err(format!("Invalid device criterion `{}`: unknown kind `{}`", spec, kind)),
+        }
+    }
+
+    fn same_kind(&self, other: &DeviceCriterion) -> bool {
+        match (self, other) {
+            (&DeviceCriterion::Name(_), &DeviceCriterion::Name(_)) => true,
+            (&DeviceCriterion::ProductId(_, _), &DeviceCriterion::ProductId(_, _)) => true,
+            (&DeviceCriterion::Type(_), &DeviceCriterion::Type(_)) => true,
+            _ => false,
+        }
+    }
+
+    pub fn matches(&self, device: &XcbInputDevice, product_id: Option<(u32, u32)>) -> bool {
+        match *self {
+            DeviceCriterion::Name(ref re) => re.is_match(&device.name),
+            DeviceCriterion::ProductId(vendor, product) => product_id == Some((vendor, product)),
+            DeviceCriterion::Type(filter) => match XcbInputDeviceType::from_u16(device._type) {
+                Some(device_type) => filter.matches(device_type),
+                None => false,
+            },
+        }
+    }
+}
+
+impl DeviceTypeFilter {
+    pub fn parse(s: &str) -> Option<DeviceTypeFilter> {
+        match s {
+            "master" => Some(DeviceTypeFilter::Master),
+            "slave" => Some(DeviceTypeFilter::Slave),
+            "master-pointer" => Some(DeviceTypeFilter::Exact(XcbInputDeviceType::MasterPointer)),
+            "master-keyboard" => Some(DeviceTypeFilter::Exact(XcbInputDeviceType::MasterKeyboard)),
+            "slave-pointer" => Some(DeviceTypeFilter::Exact(XcbInputDeviceType::SlavePointer)),
+            "slave-keyboard" => Some(DeviceTypeFilter::Exact(XcbInputDeviceType::SlaveKeyboard)),
+            "floating" => Some(DeviceTypeFilter::Exact(XcbInputDeviceType::FloatingSlave)),
+            _ => None
+        }
+    }
+
+    pub fn matches(&self, device_type: XcbInputDeviceType) -> bool {
+        match *self {
+            DeviceTypeFilter::Master => device_type == XcbInputDeviceType::MasterPointer || device_type == XcbInputDeviceType::MasterKeyboard,
+            DeviceTypeFilter::Slave => device_type == XcbInputDeviceType::SlavePointer || device_type == XcbInputDeviceType::SlaveKeyboard,
+            DeviceTypeFilter::Exact(t) => device_type == t,
+        }
+    }
+}
+
+impl DeviceMatcher {
+    pub fn parse(include: &[String], exclude: &[String]) -> Result<DeviceMatcher, String> {
+        let include_specs: Vec<&str> = if include.is_empty() {
+            vec!(DEFAULT_INCLUDE)
+        } else {
+            include.iter().map(|s| &s[..]).collect()
+        };
+        let mut matcher = DeviceMatcher { include: vec!(), exclude: vec!() };
+        for spec in include_specs.iter() {
+            matcher.include.push(try!(DeviceCriterion::parse(spec)));
+        }
+        for spec in exclude.iter() {
+            matcher.exclude.push(try!(DeviceCriterion::parse(spec)));
+        }
+        Ok(matcher)
+    }
+
+    pub fn needs_product_id(&self) -> bool {
+        self.include.iter().chain(self.exclude.iter()).any(|c| match *c {
+            DeviceCriterion::ProductId(_, _) => true,
+            _ => false,
+        })
+    }
+
+    // A device is included when, for every kind of include criterion, it matches at least
+    // one criterion of that kind; it is then rejected if it matches any exclude criterion.
+    pub fn matches(&self, device: &XcbInputDevice, product_id: Option<(u32, u32)>) -> bool {
+        let included = self.include.iter().all(|c| {
+            self.include.iter().filter(|other| other.same_kind(c)).any(|other| other.matches(device, product_id))
+        });
+        included && !self.exclude.iter().any(|c| c.matches(device, product_id))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xcb::*;
+    use std::borrow::ToOwned;
+
+    fn device(name: &str, device_type: XcbInputDeviceType) -> XcbInputDevice {
+        XcbInputDevice { deviceid: 10, _type: device_type as u16, attachment: 2, enabled: true, name: name.to_owned(), valuators: vec!() }
+    }
+
+    fn matcher(include: &[&str], exclude: &[&str]) -> DeviceMatcher {
+        let owned = |specs: &[&str]| specs.iter().map(|s| (*s).to_owned()).collect::<Vec<_>>();
+        DeviceMatcher::parse(&owned(include), &owned(exclude)).unwrap()
+    }
+
+    #[test]
+    fn includes_wacom_devices_by_default() {
+        let m = matcher(&[], &[]);
+        assert!(m.matches(&device("Wacom Intuos Pro M Pen stylus", XcbInputDeviceType::SlavePointer), None));
+        assert!(!m.matches(&device("Logitech USB Mouse", XcbInputDeviceType::SlavePointer), None));
+        assert!(!m.matches(&device("HID Wacom", XcbInputDeviceType::SlavePointer), None));
+    }
+
+    #[test]
+    fn criteria_of_one_kind_are_alternatives() {
+        let m = matcher(&["name:stylus$", "name:eraser$"], &[]);
+        assert!(m.matches(&device("Wacom Pen stylus", XcbInputDeviceType::SlavePointer), None));
+        assert!(m.matches(&device("Wacom Pen eraser", XcbInputDeviceType::SlavePointer), None));
+        assert!(!m.matches(&device("Wacom Pen pad", XcbInputDeviceType::SlavePointer), None));
+    }
+
+    #[test]
+    fn criteria_of_different_kinds_are_all_required() {
+        let m = matcher(&["name:Wacom", "type:slave-pointer", "id:56a:357"], &[]);
+        let stylus = device("Wacom Pen stylus", XcbInputDeviceType::SlavePointer);
+        assert!(m.matches(&stylus, Some((0x56a, 0x357))));
+        assert!(!m.matches(&stylus, Some((0x56a, 0x358))));
+        assert!(!m.matches(&stylus, None));
+        assert!(!m.matches(&device("Wacom Pen stylus", XcbInputDeviceType::FloatingSlave), Some((0x56a, 0x357))));
+        assert!(m.needs_product_id());
+    }
+
+    #[test]
+    fn any_exclude_criterion_rejects() {
+        let m = matcher(&[], &["name:pad$", "type:master"]);
+        assert!(m.matches(&device("Wacom Pen stylus", XcbInputDeviceType::SlavePointer), None));
+        assert!(!m.matches(&device("Wacom Pen pad", XcbInputDeviceType::SlavePointer), None));
+        assert!(!m.matches(&device("Wacom virtual pointer", XcbInputDeviceType::MasterPointer), None));
+        assert!(!m.needs_product_id());
+    }
+
+    #[test]
+    fn rejects_invalid_criteria() {
+        assert!(DeviceCriterion::parse("Wacom").is_err());
+        assert!(DeviceCriterion::parse("serial:123").is_err());
+        assert!(DeviceCriterion::parse("name:(").is_err());
+        assert!(DeviceCriterion::parse("id:56a").is_err());
+        assert!(DeviceCriterion::parse("id:56a:xyz").is_err());
+        assert!(DeviceCriterion::parse("type:tablet").is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use xcb::*;
+use ffi::xcb_randr_output_t;
+use ffi::xcb_window_t;
+use edid::*;
+use mapping::Rect;
+use std::fmt;
+use std::result::Result;
+use std::borrow::ToOwned;
+
+pub fn get_active_outputs(randr: &XcbRandr, resources: &XcbScreenResources) -> Vec<(XcbRandrOutputInfo, XcbRandrCrtcInfo)> {
+    let result = resources
+        .outputs
+        .iter()
+        .map(|output_id| randr.get_output_info(resources, *output_id).unwrap())
+        .filter(|output_info| if let XcbRandrOutputConnectionStatus::Connected = output_info.connection { true } else { false })
+        .filter(|output_info| output_info.crtc != 0)
+        .map(|output_info| {
+            let crtc_info = randr.get_crtc_info(resources, output_info.crtc).unwrap();
+            (output_info, crtc_info)
+        })
+        .collect();
+    result
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct XcbOutputDescription {
+    pub name: String,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub orientation: XcbRandrOrientation,
+    pub edid: Option<EdidIdentity>,
+    pub primary: bool,
+    pub mm_width: u32,
+    pub mm_height: u32,
+}
+
+pub fn get_output_edid(randr: &XcbRandr, output_id: xcb_randr_output_t) -> Option<EdidIdentity> {
+    let edid_atom = match randr.connection.intern_atom("EDID", true) {
+        Ok(atom) if atom != 0 => atom,
+        _ => return None,
+    };
+    match randr.get_output_property(output_id, edid_atom) {
+        Ok(property) => EdidIdentity::parse(&property.data),
+        Err(_) => None,
+    }
+}
+
+pub fn describe_output_and_crtc(randr: &XcbRandr, primary_output: xcb_randr_output_t, x: &(XcbRandrOutputInfo, XcbRandrCrtcInfo)) -> XcbOutputDescription {
+    XcbOutputDescription {
+        name: x.0.name.clone(),
+        x: x.1.x,
+        y: x.1.y,
+        width: x.1.width,
+        height: x.1.height,
+        orientation: XcbRandrOrientation::from_u16(x.1.rotation),
+        edid: get_output_edid(randr, x.0.id),
+        primary: x.0.id == primary_output,
+        mm_width: x.0.mm_width,
+        mm_height: x.0.mm_height,
+    }
+}
+
+pub fn describe_active_outputs(randr: &XcbRandr, resources: &XcbScreenResources, root_window_id: xcb_window_t) -> Vec<XcbOutputDescription> {
+    let primary_output = randr.get_output_primary(root_window_id).unwrap_or(0);
+    get_active_outputs(randr, resources)
+        .iter()
+        .map(|x| describe_output_and_crtc(randr, primary_output, x))
+        .collect()
+}
+
+impl XcbOutputDescription {
+    pub fn rect(&self) -> Rect {
+        Rect { x: self.x as f32, y: self.y as f32, width: self.width as f32, height: self.height as f32 }
+    }
+    
+    pub fn is_builtin(&self) -> bool {
+        BUILTIN_CONNECTORS.iter().any(|prefix| self.name.starts_with(prefix))
+    }
+}
+
+const BUILTIN_CONNECTORS: [&'static str; 3] = ["eDP", "LVDS", "DSI"];
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum OutputSelector {
+    Name(String),
+    Edid(EdidSelector),
+    Primary,
+    Builtin,
+    External,
+    Leftmost,
+    Rightmost,
+    Largest,
+    LargestPhysical,
+}
+
+impl OutputSelector {
+    pub fn parse(s: &str) -> Result<OutputSelector, String> {
+        match s {
+            "primary" => Ok(OutputSelector::Primary),
+            "builtin" => Ok(OutputSelector::Builtin),
+            "external" => Ok(OutputSelector::External),
+            "leftmost" => Ok(OutputSelector::Leftmost),
+            "rightmost" => Ok(OutputSelector::Rightmost),
+            "largest" => Ok(OutputSelector::Largest),
+            "largest-physical" => Ok(OutputSelector::LargestPhysical),
+            "" => Err("Output name must not be empty".to_owned()),
+            _ if s.starts_with("edid:") => Ok(OutputSelector::Edid(try!(EdidSelector::parse(&s["edid:".len()..])))),
+            _ => Ok(OutputSelector::Name(s.to_owned())),
+        }
+    }
+
+    pub fn parse_list<S: AsRef<str>>(items: &[S]) -> Result<Vec<OutputSelector>, String> {
+        let mut selectors = vec!();
+        for item in items.iter() {
+            selectors.push(try!(OutputSelector::parse(item.as_ref().trim())));
+        }
+        if selectors.is_empty() {
+            return Err("Output list must not be empty".to_owned());
+        }
+        Ok(selectors)
+    }
+
+    // Picks the output denoted by this selector among the currently active outputs. Ties go to
+    // the topmost output and then to the first one, as max_by_key would pick the last.
+    pub fn resolve<'a>(&self, outputs: &'a [XcbOutputDescription]) -> Option<&'a XcbOutputDescription> {
+        match *self {
+            OutputSelector::Name(ref name) => outputs.iter().filter(|o| o.name == *name).nth(0),
+            OutputSelector::Edid(ref selector) => outputs.iter().filter(|o| match o.edid {
+                Some(ref identity) => selector.matches(identity),
+                None => false,
+            }).nth(0),
+            OutputSelector::Primary => outputs.iter().filter(|o| o.primary).nth(0),
+            OutputSelector::Builtin => outputs.iter().filter(|o| o.is_builtin()).nth(0),
+            OutputSelector::External => outputs.iter().filter(|o| !o.is_builtin()).nth(0),
+            OutputSelector::Leftmost => outputs.iter().min_by_key(|o| (o.x, o.y)),
+            OutputSelector::Rightmost => outputs.iter().rev().max_by_key(|o| (o.x as i32 + o.width as i32, -(o.y as i32))),
+            OutputSelector::Largest => outputs.iter().rev().max_by_key(|o| o.width as u32 * o.height as u32),
+            OutputSelector::LargestPhysical => outputs.iter().rev().max_by_key(|o| o.mm_width as u64 * o.mm_height as u64),
+        }
+    }
+}
+
+impl fmt::Display for OutputSelector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            OutputSelector::Name(ref name) => write!(fmt, "{}", name),
+            OutputSelector::Edid(ref selector) => write!(fmt, "{}", selector),
+            OutputSelector::Primary => write!(fmt, "primary"),
+            OutputSelector::Builtin => write!(fmt, "builtin"),
+            OutputSelector::External => write!(fmt, "external"),
+            OutputSelector::Leftmost => write!(fmt, "leftmost"),
+            OutputSelector::Rightmost => write!(fmt, "rightmost"),
+            OutputSelector::Largest => write!(fmt, "largest"),
+            OutputSelector::LargestPhysical => write!(fmt, "largest-physical"),
+        }
+    }
+}
+
+// Picks the first active output in the order of preference
+pub fn find_output<'a>(outputs: &'a [XcbOutputDescription], selectors: &[OutputSelector]) -> Option<&'a XcbOutputDescription> {
+    selectors.iter().filter_map(|selector| selector.resolve(outputs)).nth(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(name: &str, x: i16, y: i16, width: u16, height: u16, mm_width: u32, mm_height: u32) -> XcbOutputDescription {
+        XcbOutputDescription {
+            name: name.to_owned(),
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+            orientation: XcbRandrOrientation::from_u16(1),
+            edid: None,
+            primary: false,
+            mm_width: mm_width,
+            mm_height: mm_height,
+        }
+    }
+
+    fn resolve(spec: &str, outputs: &[XcbOutputDescription]) -> Option<String> {
+        OutputSelector::parse(spec).unwrap().resolve(outputs).map(|o| o.name.clone())
+    }
+
+    // A laptop panel below two external monitors of different sizes
+    fn docked() -> Vec<XcbOutputDescription> {
+        vec!(
+            output("DP-1", 0, 0, 2560, 1440, 600, 340),
+            output("HDMI-1", 2560, 0, 1920, 1080, 700, 390),
+            output("eDP-1", 640, 1440, 1920, 1200, 300, 190),
+        )
+    }
+
+    #[test]
+    fn resolves_position_and_size_selectors() {
+        let outputs = docked();
+        assert_eq!(resolve("leftmost", &outputs), Some("DP-1".to_owned()));
+        assert_eq!(resolve("rightmost", &outputs), Some("HDMI-1".to_owned()));
+        assert_eq!(resolve("largest", &outputs), Some("DP-1".to_owned()));
+        assert_eq!(resolve("largest-physical", &outputs), Some("HDMI-1".to_owned()));
+        assert_eq!(resolve("builtin", &outputs), Some("eDP-1".to_owned()));
+        assert_eq!(resolve("external", &outputs), Some("DP-1".to_owned()));
+        assert_eq!(resolve("HDMI-1", &outputs), Some("HDMI-1".to_owned()));
+    }
+
+    #[test]
+    fn ties_go_to_the_topmost_then_the_first_output() {
+        // Two monitors stacked on the left, mirrored panels on the right
+        let outputs = vec!(
+            output("DP-2", 0, 1080, 1920, 1080, 500, 300),
+            output("DP-1", 0, 0, 1920, 1080, 500, 300),
+            output("LVDS-1", 1920, 0, 1920, 1080, 500, 300),
+            output("LVDS-2", 1920, 0, 1920, 1080, 500, 300),
+        );
+        assert_eq!(resolve("leftmost", &outputs), Some("DP-1".to_owned()));
+        assert_eq!(resolve("rightmost", &outputs), Some("LVDS-1".to_owned()));
+        assert_eq!(resolve("largest", &outputs), Some("DP-2".to_owned()));
+        assert_eq!(resolve("largest-physical", &outputs), Some("DP-2".to_owned()));
+        assert_eq!(resolve("builtin", &outputs), Some("LVDS-1".to_owned()));
+        assert_eq!(resolve("external", &outputs), Some("DP-2".to_owned()));
+    }
+
+    #[test]
+    fn resolves_nothing_without_a_match() {
+        for spec in ["leftmost", "rightmost", "largest", "largest-physical", "builtin", "external", "primary", "DP-1"].iter() {
+            assert_eq!(resolve(spec, &[]), None);
+        }
+        let externals = vec!(output("DP-1", 0, 0, 1920, 1080, 500, 300));
+        assert_eq!(resolve("builtin", &externals), None);
+        assert_eq!(resolve("primary", &externals), None);
+        assert_eq!(resolve("HDMI-1", &externals), None);
+        let panels = vec!(output("eDP-1", 0, 0, 1920, 1080, 300, 190), output("DSI-1", 1920, 0, 800, 1280, 100, 160));
+        assert_eq!(resolve("external", &panels), None);
+    }
+}
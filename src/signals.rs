@@ -0,0 +1,126 @@
+use libc::c_int;
+use libc::c_void;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::atomic::ATOMIC_BOOL_INIT;
+use std::sync::atomic::ATOMIC_USIZE_INIT;
+use sys;
+
+// Last termination signal received, 0 if none. The handler only records the signal and
+// writes to the self-pipe, so that the event loop wakes up even when the signal arrives just
+// before it calls poll().
+static RECEIVED_SIGNAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Both ends of the self-pipe plus one, 0 until install_termination_handlers has created it
+static WAKE_UP_READ_FD: AtomicUsize = ATOMIC_USIZE_INIT;
+static WAKE_UP_WRITE_FD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn on_signal(signum: c_int) {
+    RECEIVED_SIGNAL.store(signum as usize, Ordering::SeqCst);
+    wake_up();
+}
+
+// Only async-signal-safe calls; errno is preserved for the code the signal interrupted
+fn wake_up() {
+    let fd = WAKE_UP_WRITE_FD.load(Ordering::SeqCst);
+    if fd == 0 {
+        return;
+    }
+    unsafe {
+        let errno = *sys::__errno_location();
+        // A full pipe is fine: the loop has not read the previous bytes yet
+        sys::write(fd as c_int - 1, b"\0".as_ptr() as *const c_void, 1);
+        *sys::__errno_location() = errno;
+    }
+}
+
+// Set by SIGHUP, cleared by take_reload_request
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_reload_signal(_: c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+    wake_up();
+}
+
+// Handles SIGINT, SIGTERM and SIGHUP, so that the watcher can clean up before exiting
+pub fn install_termination_handlers() {
+    if let Err(e) = create_wake_up_pipe() {
+        warn!("Failed to create the signal pipe, signals may only be noticed after the next event: {}", e);
+    }
+    for signum in [sys::SIGINT, sys::SIGTERM, sys::SIGHUP].iter() {
+        unsafe { sys::signal(*signum, on_signal as sys::sighandler_t) };
+    }
+}
+
+fn create_wake_up_pipe() -> io::Result<()> {
+    if WAKE_UP_READ_FD.load(Ordering::SeqCst) != 0 {
+        return Ok(());
+    }
+    let mut fds = [0 as c_int; 2];
+    if unsafe { sys::pipe2(fds.as_mut_ptr(), sys::O_NONBLOCK | sys::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    WAKE_UP_READ_FD.store(fds[0] as usize + 1, Ordering::SeqCst);
+    WAKE_UP_WRITE_FD.store(fds[1] as usize + 1, Ordering::SeqCst);
+    Ok(())
+}
+
+// Becomes readable when a signal has been received; to be polled with the other descriptors
+pub fn wake_up_fd() -> Option<c_int> {
+    match WAKE_UP_READ_FD.load(Ordering::SeqCst) {
+        0 => None,
+        fd => Some(fd as c_int - 1),
+    }
+}
+
+// Empties the self-pipe. Must be called before the flags are checked, so that a signal that
+// arrives in between still wakes up the next poll().
+pub fn clear_wake_up() {
+    if let Some(fd) = wake_up_fd() {
+        let mut buffer = [0u8; 64];
+        while unsafe { sys::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) } > 0 {}
+    }
+}
+
+pub fn received_signal() -> Option<c_int> {
+    match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum as c_int),
+    }
+}
+
+// Handles SIGHUP as the conventional request to reload the configuration instead of as a
+// termination signal. Only makes sense when there is a configuration file to reload.
+pub fn install_reload_handler() {
+    unsafe { sys::signal(sys::SIGHUP, on_reload_signal as sys::sighandler_t) };
+}
+
+// True once for every SIGHUP received since the last call
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readable(fd: c_int) -> bool {
+        let mut pollfd = sys::pollfd { fd: fd, events: sys::POLLIN, revents: 0 };
+        unsafe { sys::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn termination_signal_wakes_up_poll() {
+        install_termination_handlers();
+        let fd = wake_up_fd().unwrap();
+        clear_wake_up();
+        assert!(!readable(fd));
+        unsafe { sys::raise(sys::SIGTERM) };
+        assert_eq!(received_signal(), Some(sys::SIGTERM));
+        assert!(readable(fd));
+        clear_wake_up();
+        assert!(!readable(fd));
+    }
+}
@@ -0,0 +1,100 @@
+// Bits of libc that are not exposed by the libc crate
+#![allow(non_camel_case_types)]
+use libc::*;
+
+#[repr(C)]
+pub struct pollfd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+pub const POLLIN: c_short = 0x1;
+
+extern "C" {
+    pub fn poll(fds: *mut pollfd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+pub type sighandler_t = size_t;
+
+pub const SIGHUP: c_int = 1;
+pub const SIGINT: c_int = 2;
+pub const SIGTERM: c_int = 15;
+
+extern "C" {
+    pub fn signal(signum: c_int, handler: sighandler_t) -> sighandler_t;
+    #[cfg(test)]
+    pub fn raise(signum: c_int) -> c_int;
+}
+
+pub const STDOUT_FILENO: c_int = 1;
+pub const STDERR_FILENO: c_int = 2;
+
+extern "C" {
+    pub fn dup(fd: c_int) -> c_int;
+    pub fn dup2(fd: c_int, fd2: c_int) -> c_int;
+}
+
+extern "C" {
+    pub fn getpid() -> c_int;
+    pub fn getuid() -> c_uint;
+}
+
+pub const IN_CLOEXEC: c_int = 0o2000000;
+pub const IN_NONBLOCK: c_int = 0o4000;
+
+pub const IN_CLOSE_WRITE: uint32_t = 0x8;
+pub const IN_MOVED_TO: uint32_t = 0x80;
+pub const IN_CREATE: uint32_t = 0x100;
+pub const IN_DELETE: uint32_t = 0x200;
+pub const IN_Q_OVERFLOW: uint32_t = 0x4000;
+
+// Followed by `len` bytes of NUL-padded file name
+#[repr(C)]
+pub struct inotify_event {
+    pub wd: c_int,
+    pub mask: uint32_t,
+    pub cookie: uint32_t,
+    pub len: uint32_t,
+}
+
+extern "C" {
+    pub fn inotify_init1(flags: c_int) -> c_int;
+    pub fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: uint32_t) -> c_int;
+}
+
+pub const AF_UNIX: c_int = 1;
+pub const SOCK_STREAM: c_int = 1;
+pub const SOCK_CLOEXEC: c_int = 0o2000000;
+
+#[repr(C)]
+pub struct sockaddr_un {
+    pub sun_family: c_ushort,
+    pub sun_path: [c_char; 108],
+}
+
+extern "C" {
+    pub fn socket(domain: c_int, socket_type: c_int, protocol: c_int) -> c_int;
+    pub fn connect(fd: c_int, addr: *const sockaddr_un, len: c_uint) -> c_int;
+    pub fn close(fd: c_int) -> c_int;
+}
+
+pub const O_NONBLOCK: c_int = 0o4000;
+pub const O_CLOEXEC: c_int = 0o2000000;
+
+extern "C" {
+    pub fn pipe2(fds: *mut c_int, flags: c_int) -> c_int;
+    pub fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t;
+    pub fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+    pub fn __errno_location() -> *mut c_int;
+}
+
+pub const F_GETFL: c_int = 3;
+pub const F_SETFL: c_int = 4;
+pub const ENXIO: c_int = 6;
+
+extern "C" {
+    pub fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    #[cfg(test)]
+    pub fn mkfifo(pathname: *const c_char, mode: c_uint) -> c_int;
+}
@@ -13,6 +13,7 @@ use std::ops::Deref;
 use std::marker::PhantomData;
 use std::str;
 use std::slice;
+use sys;
 
 #[derive(Debug)]
 #[allow(raw_pointer_derive)]
@@ -26,10 +27,22 @@ pub struct XcbSetup<'a> {
 }
 
 pub struct XcbRandr<'a> {
-    connection: &'a XcbConnection,
+    pub connection: &'a XcbConnection,
     pub extension: xcb_query_extension_reply_t,
+    // Version negotiated with the server, as (major, minor)
+    pub version: (uint32_t, uint32_t),
 }
 
+// Highest versions of the extensions this program knows about. The server answers with the
+// lower of these and its own version.
+const RANDR_VERSION: (uint32_t, uint32_t) = (1, 4);
+const XINPUT_VERSION: (uint16_t, uint16_t) = (2, 2);
+// Oldest versions that provide what is always needed: outputs and CRTCs for RANDR, hierarchy
+// events and device properties for XInput. Nothing newer than XInput 2.2 is used, so that is
+// also the only version asked for.
+const RANDR_MIN_VERSION: (uint32_t, uint32_t) = (1, 2);
+const XINPUT_MIN_VERSION: (uint16_t, uint16_t) = XINPUT_VERSION;
+
 impl XcbConnection {
     pub fn new_default() -> XcbConnection {
         let mut screen_num = 0;
@@ -42,13 +55,158 @@ impl XcbConnection {
       XcbSetup { value: s }
     }
     
-    pub fn wait_for_event(&self) -> Result<LibcPtr<xcb_generic_event_t>, XcbError> {
-        let event_ptr = unsafe { xcb_wait_for_event(self.value) };
-        if event_ptr == 0 as *mut _ {
+    pub fn poll_for_event(&self) -> Result<Option<LibcPtr<xcb_generic_event_t>>, XcbError> {
+        let event_ptr = unsafe { xcb_poll_for_event(self.value) };
+        if event_ptr != 0 as *mut _ {
+            Ok(Some(LibcPtr::new(event_ptr)))
+        } else if unsafe { xcb_connection_has_error(self.value) } != 0 {
             Err(XcbError::IOError)
         } else {
-            Ok(LibcPtr::new(event_ptr))
+            Ok(None)
+        }
+    }
+    
+    // Waits for at most `timeout_ms` milliseconds (forever if negative) for an event. Unlike
+    // wait_for_event, returns None when interrupted by a signal.
+    pub fn wait_for_event_timeout(&self, timeout_ms: c_int) -> Result<Option<LibcPtr<xcb_generic_event_t>>, XcbError> {
+        self.wait_for_event_or_fds(timeout_ms, &[])
+    }
+    
+    // Same as wait_for_event_timeout, but also returns None as soon as one of `fds` is readable
+    pub fn wait_for_event_or_fds(&self, timeout_ms: c_int, fds: &[c_int]) -> Result<Option<LibcPtr<xcb_generic_event_t>>, XcbError> {
+        if let Some(event) = try!(self.poll_for_event()) {
+            return Ok(Some(event));
         }
+        self.flush();
+        let mut pollfds = vec!(sys::pollfd { fd: self.get_file_descriptor(), events: sys::POLLIN, revents: 0 });
+        pollfds.extend(fds.iter().map(|fd| sys::pollfd { fd: *fd, events: sys::POLLIN, revents: 0 }));
+        unsafe { sys::poll(pollfds.as_mut_ptr(), pollfds.len() as c_ulong, timeout_ms) };
+        self.poll_for_event()
+    }
+    
+    pub fn get_file_descriptor(&self) -> c_int {
+        unsafe { xcb_get_file_descriptor(self.value) }
+    }
+    
+    pub fn flush(&self) {
+        unsafe { xcb_flush(self.value) };
+    }
+    
+    pub fn query_pointer(&self, window: xcb_window_t) -> Result<(i16, i16), XcbError> {
+        let cookie = unsafe { xcb_query_pointer(self.value, window) };
+        let reply = try!(get_reply(self, cookie, xcb_query_pointer_reply));
+        Ok((reply.root_x, reply.root_y))
+    }
+    
+    // Values of a window property with 32-bit items (e.g. WINDOW or CARDINAL)
+    pub fn get_window_property_u32(&self, window: xcb_window_t, property: xcb_atom_t, proptype: xcb_atom_t) -> Result<Vec<u32>, XcbError> {
+        let cookie = unsafe { xcb_get_property(self.value, 0, window, property, proptype, 0, 256) };
+        let reply = try!(get_reply(self, cookie, xcb_get_property_reply));
+        if reply._type != proptype || reply.format != 32 {
+            return Ok(vec!());
+        }
+        let values = unsafe {
+            slice::from_raw_parts(
+                xcb_get_property_value(reply.value) as *const uint32_t,
+                xcb_get_property_value_length(reply.value) as usize / 4
+            )
+        }.to_vec();
+        Ok(values)
+    }
+    
+    pub fn get_window_parent(&self, window: xcb_window_t) -> Result<xcb_window_t, XcbError> {
+        let cookie = unsafe { xcb_query_tree(self.value, window) };
+        let reply = try!(get_reply(self, cookie, xcb_query_tree_reply));
+        Ok(reply.parent)
+    }
+    
+    // Position (relative to `root`) and size of the window, excluding its border
+    pub fn get_window_rect(&self, window: xcb_window_t, root: xcb_window_t) -> Result<(i16, i16, u16, u16), XcbError> {
+        let geometry_cookie = unsafe { xcb_get_geometry(self.value, window) };
+        let translate_cookie = unsafe { xcb_translate_coordinates(self.value, window, root, 0, 0) };
+        let geometry = try!(get_reply(self, geometry_cookie, xcb_get_geometry_reply));
+        let translated = try!(get_reply(self, translate_cookie, xcb_translate_coordinates_reply));
+        Ok((translated.dst_x, translated.dst_y, geometry.width, geometry.height))
+    }
+    
+    pub fn select_window_events(&self, window: xcb_window_t, event_mask: u32) -> Result<(), XcbError> {
+        let cookie = unsafe {
+            xcb_change_window_attributes_checked(self.value, window, XCB_CW_EVENT_MASK, &event_mask as *const uint32_t)
+        };
+        try!(wait_for_cookie(self, cookie));
+        Ok(())
+    }
+    
+    // Unmapped 1x1 InputOnly window, e.g. to own selections
+    pub fn create_input_only_window(&self, parent: xcb_window_t) -> Result<xcb_window_t, XcbError> {
+        let window = unsafe { xcb_generate_id(self.value) };
+        let override_redirect: uint32_t = 1;
+        let cookie = unsafe {
+            xcb_create_window_checked(
+                self.value,
+                0, // depth: copy from parent
+                window,
+                parent,
+                -1, -1, 1, 1, // x, y, width, height
+                0, // border_width
+                XCB_WINDOW_CLASS_INPUT_ONLY as uint16_t,
+                0, // visual: copy from parent
+                XCB_CW_OVERRIDE_REDIRECT,
+                &override_redirect as *const uint32_t
+            )
+        };
+        try!(wait_for_cookie(self, cookie));
+        Ok(window)
+    }
+    
+    pub fn get_selection_owner(&self, selection: xcb_atom_t) -> Result<xcb_window_t, XcbError> {
+        let cookie = unsafe { xcb_get_selection_owner(self.value, selection) };
+        let reply = try!(get_reply(self, cookie, xcb_get_selection_owner_reply));
+        Ok(reply.owner)
+    }
+    
+    pub fn set_selection_owner(&self, owner: xcb_window_t, selection: xcb_atom_t, time: xcb_timestamp_t) -> Result<(), XcbError> {
+        let cookie = unsafe { xcb_set_selection_owner_checked(self.value, owner, selection, time) };
+        try!(wait_for_cookie(self, cookie));
+        Ok(())
+    }
+    
+    pub fn get_keyboard_mapping(&self, first_keycode: xcb_keycode_t, count: u8) -> Result<XcbKeyboardMapping, XcbError> {
+        let cookie = unsafe { xcb_get_keyboard_mapping(self.value, first_keycode, count) };
+        let reply = try!(get_reply(self, cookie, xcb_get_keyboard_mapping_reply));
+        let keysyms = unsafe {
+            slice::from_raw_parts(
+                xcb_get_keyboard_mapping_keysyms(reply.value),
+                xcb_get_keyboard_mapping_keysyms_length(reply.value) as usize
+            )
+        }.to_vec();
+        Ok(XcbKeyboardMapping {
+            first_keycode: first_keycode,
+            keysyms_per_keycode: reply.keysyms_per_keycode,
+            keysyms: keysyms,
+        })
+    }
+    
+    pub fn grab_key(&self, window: xcb_window_t, modifiers: u16, key: xcb_keycode_t) -> Result<(), XcbError> {
+        let cookie = unsafe {
+            xcb_grab_key_checked(
+                self.value,
+                1, // owner_events
+                window,
+                modifiers,
+                key,
+                XCB_GRAB_MODE_ASYNC as uint8_t,
+                XCB_GRAB_MODE_ASYNC as uint8_t
+            )
+        };
+        try!(wait_for_cookie(self, cookie));
+        Ok(())
+    }
+    
+    pub fn ungrab_key(&self, window: xcb_window_t, modifiers: u16, key: xcb_keycode_t) -> Result<(), XcbError> {
+        let cookie = unsafe { xcb_ungrab_key_checked(self.value, key, window, modifiers) };
+        try!(wait_for_cookie(self, cookie));
+        Ok(())
     }
     
     pub fn intern_atom(&self, name: &str, only_if_exists: bool) -> Result<xcb_atom_t, XcbError> {
@@ -56,9 +214,29 @@ impl XcbConnection {
         let reply = try!(get_reply(self, cookie, xcb_intern_atom_reply));
         Ok(reply.atom)
     }
+    
+    pub fn get_atom_name(&self, atom: xcb_atom_t) -> Result<String, XcbError> {
+        let cookie = unsafe { xcb_get_atom_name(self.value, atom) };
+        let reply = try!(get_reply(self, cookie, xcb_get_atom_name_reply));
+        let name = unsafe {
+            slice::from_raw_parts(
+                xcb_get_atom_name_name(reply.value) as *const u8,
+                xcb_get_atom_name_name_length(reply.value) as usize
+            )
+        };
+        Ok(String::from_utf8_lossy(name).into_owned())
+    }
 }
 
 impl <'a> XcbSetup<'a> {
+    pub fn min_keycode(&self) -> xcb_keycode_t {
+        self.value.min_keycode
+    }
+    
+    pub fn max_keycode(&self) -> xcb_keycode_t {
+        self.value.max_keycode
+    }
+    
     pub fn roots_iterator(&'a self) -> XcbIterator<'a, xcb_screen_iterator_t, xcb_screen_t> {
         let ffi_it = unsafe { xcb_setup_roots_iterator(self.value) };
         XcbIterator::new(ffi_it, xcb_screen_next)
@@ -179,6 +357,28 @@ impl Debug for xcb_generic_error_t {
 }
 
 
+#[derive(Debug)]
+pub struct XcbKeyboardMapping {
+    pub first_keycode: xcb_keycode_t,
+    pub keysyms_per_keycode: uint8_t,
+    pub keysyms: Vec<xcb_keysym_t>,
+}
+
+impl XcbKeyboardMapping {
+    pub fn keycodes_for(&self, keysym: xcb_keysym_t) -> Vec<xcb_keycode_t> {
+        let per_keycode = self.keysyms_per_keycode as usize;
+        if per_keycode == 0 {
+            return vec!();
+        }
+        self.keysyms
+            .chunks(per_keycode)
+            .enumerate()
+            .filter(|&(_, syms)| syms.contains(&keysym))
+            .map(|(idx, _)| self.first_keycode + idx as xcb_keycode_t)
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub enum XcbError {
     ProtoError(xcb_generic_error_t),
@@ -197,10 +397,22 @@ impl <'a> XcbRandr<'a> {
         let cookie = unsafe { xcb_query_extension(connection.value, 5, "RANDR".as_ptr() as *const c_char) };
         let reply = *try!(get_reply(connection, cookie, xcb_query_extension_reply));
         if reply.present == 0 {
-            Err(XcbError::LogicError("RANDR extension is not present".to_owned()))
-        } else {
-            Ok(XcbRandr { connection: connection, extension: reply })
+            return Err(XcbError::LogicError("RANDR extension is not present".to_owned()));
         }
+        
+        let cookie = unsafe { xcb_randr_query_version(connection.value, RANDR_VERSION.0, RANDR_VERSION.1) };
+        let version_reply = try!(get_reply(connection, cookie, xcb_randr_query_version_reply));
+        let version = (version_reply.major_version, version_reply.minor_version);
+        if version < RANDR_MIN_VERSION {
+            return Err(XcbError::LogicError(format!(
+                "RANDR {}.{} is too old, at least {}.{} is required", version.0, version.1, RANDR_MIN_VERSION.0, RANDR_MIN_VERSION.1
+            )));
+        }
+        Ok(XcbRandr { connection: connection, extension: reply, version: version })
+    }
+    
+    pub fn supports(&self, major: uint32_t, minor: uint32_t) -> bool {
+        self.version >= (major, minor)
     }
     
     pub fn get_screen_resources(&self, root_window_id: xcb_window_t) -> Result<XcbScreenResources, XcbError> {
@@ -264,23 +476,99 @@ impl <'a> XcbRandr<'a> {
             y: reply.y,
             width: reply.width,
             height: reply.height,
+            rotation: reply.rotation,
         })
     }
     
-    pub fn select_input(&self, window: xcb_window_t) -> Result<(), XcbError> {
+    // XCB_NONE before RANDR 1.3, which introduced the primary output
+    pub fn get_output_primary(&self, window: xcb_window_t) -> Result<xcb_randr_output_t, XcbError> {
+        if !self.supports(1, 3) {
+            return Ok(0 /* XCB_NONE */);
+        }
+        let cookie = unsafe { xcb_randr_get_output_primary(self.connection.value, window) };
+        let reply = try!(get_reply(self.connection, cookie, xcb_randr_get_output_primary_reply));
+        Ok(reply.output)
+    }
+    
+    pub fn get_output_property(&self, output_id: xcb_randr_output_t, property: xcb_atom_t) -> Result<XcbRandrOutputProperty, XcbError> {
         let cookie = unsafe {
-            xcb_randr_select_input_checked(
+            xcb_randr_get_output_property(
                 self.connection.value,
-                window,
-                XCB_RANDR_NOTIFY_MASK_SCREEN_CHANGE as u16 | 
-                XCB_RANDR_NOTIFY_MASK_CRTC_CHANGE as u16 | 
-                XCB_RANDR_NOTIFY_MASK_OUTPUT_CHANGE as u16 | 
-                XCB_RANDR_NOTIFY_MASK_OUTPUT_PROPERTY as u16 | 
-                XCB_RANDR_NOTIFY_MASK_PROVIDER_CHANGE as u16 | 
-                XCB_RANDR_NOTIFY_MASK_PROVIDER_PROPERTY as u16 | 
-                XCB_RANDR_NOTIFY_MASK_RESOURCE_CHANGE as u16
+                output_id,
+                property,
+                XCB_GET_PROPERTY_TYPE_ANY as xcb_atom_t,
+                0, // long_offset
+                256, // long_length, in 32-bit units
+                0, // delete
+                0 // pending
             )
         };
+        let reply = try!(get_reply(self.connection, cookie, xcb_randr_get_output_property_reply));
+        let data = unsafe {
+            slice::from_raw_parts(
+                xcb_randr_get_output_property_data(reply.value),
+                xcb_randr_get_output_property_data_length(reply.value) as usize
+            )
+        }.to_vec();
+        Ok(XcbRandrOutputProperty {
+            proptype: reply._type,
+            format: reply.format,
+            data: data,
+        })
+    }
+    
+    // Decodes ScreenChangeNotify and Notify events, None for other events
+    pub fn decode_event(&self, event: &xcb_generic_event_t) -> Option<XcbRandrEvent> {
+        if event.response_type < self.extension.first_event {
+            return None;
+        }
+        match event.response_type - self.extension.first_event {
+            0 /* XCB_RANDR_SCREEN_CHANGE_NOTIFY */ => {
+                let e = unsafe { &*(event as *const _ as *const xcb_randr_screen_change_notify_event_t) };
+                Some(XcbRandrEvent::ScreenChange { width: e.width, height: e.height, rotation: e.rotation as u16 })
+            },
+            1 /* XCB_RANDR_NOTIFY */ => {
+                let mut e = unsafe { *(event as *const _ as *const xcb_randr_notify_event_t) };
+                match XcbRandrEventType::from_u8(e.subCode) {
+                    Some(XcbRandrEventType::CrtcChange) => {
+                        let cc = unsafe { *e.u.cc() };
+                        Some(XcbRandrEvent::CrtcChange { crtc: cc.crtc, x: cc.x, y: cc.y, width: cc.width, height: cc.height, rotation: cc.rotation })
+                    },
+                    Some(XcbRandrEventType::OutputChange) => {
+                        let oc = unsafe { *e.u.oc() };
+                        Some(XcbRandrEvent::OutputChange {
+                            output: oc.output,
+                            crtc: oc.crtc,
+                            connection: XcbRandrOutputConnectionStatus::from_u8(oc.connection).unwrap_or(XcbRandrOutputConnectionStatus::Unknown),
+                        })
+                    },
+                    Some(XcbRandrEventType::OutputProperty) => {
+                        let op = unsafe { *e.u.op() };
+                        Some(XcbRandrEvent::OutputProperty {
+                            output: op.output,
+                            property: self.connection.get_atom_name(op.atom).unwrap_or(String::new()),
+                        })
+                    },
+                    Some(event_type) => Some(XcbRandrEvent::Other(event_type)),
+                    None => None,
+                }
+            },
+            _ => None,
+        }
+    }
+    
+    pub fn select_input(&self, window: xcb_window_t) -> Result<(), XcbError> {
+        let mut mask = XCB_RANDR_NOTIFY_MASK_SCREEN_CHANGE as u16 | 
+            XCB_RANDR_NOTIFY_MASK_CRTC_CHANGE as u16 | 
+            XCB_RANDR_NOTIFY_MASK_OUTPUT_CHANGE as u16 | 
+            XCB_RANDR_NOTIFY_MASK_OUTPUT_PROPERTY as u16;
+        // Providers and the resource change notification are new in RANDR 1.4; older servers reject the bits
+        if self.supports(1, 4) {
+            mask |= XCB_RANDR_NOTIFY_MASK_PROVIDER_CHANGE as u16 | 
+                XCB_RANDR_NOTIFY_MASK_PROVIDER_PROPERTY as u16 | 
+                XCB_RANDR_NOTIFY_MASK_RESOURCE_CHANGE as u16;
+        }
+        let cookie = unsafe { xcb_randr_select_input_checked(self.connection.value, window, mask) };
         try!(wait_for_cookie(self.connection, cookie));
         Ok(())
     }
@@ -356,6 +644,59 @@ pub struct XcbRandrCrtcInfo {
     pub y: int16_t,
     pub width: uint16_t,
     pub height: uint16_t,
+    pub rotation: uint16_t,
+}
+
+#[derive(Debug)]
+pub struct XcbRandrOutputProperty {
+    pub proptype: xcb_atom_t,
+    pub format: uint8_t,
+    pub data: Vec<uint8_t>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum XcbRandrRotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct XcbRandrOrientation {
+    pub rotation: XcbRandrRotation,
+    pub reflect_x: bool,
+    pub reflect_y: bool,
+}
+
+impl XcbRandrOrientation {
+    pub fn from_u16(val: u16) -> XcbRandrOrientation {
+        let val = val as c_uint;
+        let rotation = if val & XCB_RANDR_ROTATION_ROTATE_90 != 0 {
+            XcbRandrRotation::Rotate90
+        } else if val & XCB_RANDR_ROTATION_ROTATE_180 != 0 {
+            XcbRandrRotation::Rotate180
+        } else if val & XCB_RANDR_ROTATION_ROTATE_270 != 0 {
+            XcbRandrRotation::Rotate270
+        } else {
+            XcbRandrRotation::Rotate0
+        };
+        XcbRandrOrientation {
+            rotation: rotation,
+            reflect_x: val & XCB_RANDR_ROTATION_REFLECT_X != 0,
+            reflect_y: val & XCB_RANDR_ROTATION_REFLECT_Y != 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum XcbRandrEvent {
+    ScreenChange { width: u16, height: u16, rotation: u16 },
+    CrtcChange { crtc: xcb_randr_crtc_t, x: i16, y: i16, width: u16, height: u16, rotation: u16 },
+    OutputChange { output: xcb_randr_output_t, crtc: xcb_randr_crtc_t, connection: XcbRandrOutputConnectionStatus },
+    OutputProperty { output: xcb_randr_output_t, property: String },
+    // Provider and resource changes
+    Other(XcbRandrEventType),
 }
 
 #[derive(Debug)]
@@ -396,20 +737,37 @@ impl <'a> XcbInput<'a> {
             return Err(XcbError::LogicError(format!("{} extension is not present", str::from_utf8(xcb_input_extension_name.to_bytes()).unwrap())))
         }
         
-        {
-            let cookie = unsafe { xcb_input_xi_query_version(connection.value, 2, 3) };
+        let version = {
+            let cookie = unsafe { xcb_input_xi_query_version(connection.value, XINPUT_VERSION.0, XINPUT_VERSION.1) };
             let reply = try!(get_reply(connection, cookie, xcb_input_xi_query_version_reply));
-            
-            if reply.major_version != 2 || reply.minor_version != 3 {
-                return Err(XcbError::LogicError(format!("Invalid XINPUT version")));
-            }
+            (reply.major_version, reply.minor_version)
+        };
+        // A later major version would not be compatible with the requests made here
+        if version.0 != XINPUT_MIN_VERSION.0 || version < XINPUT_MIN_VERSION {
+            return Err(XcbError::LogicError(format!(
+                "XInput {}.{} is not supported, {}.{} or a later {}.x is required",
+                version.0, version.1, XINPUT_MIN_VERSION.0, XINPUT_MIN_VERSION.1, XINPUT_MIN_VERSION.0
+            )));
         }
         
         Ok(XcbInput { connection: connection, extension: reply })
     }
     
     pub fn get_devices(&self) -> Result<XcbInputDevices, XcbError> {
-        let cookie = unsafe { xcb_input_xi_query_device(self.connection.value, 0) }; // 0 == AllDevices
+        self.query_devices(0) // 0 == AllDevices
+    }
+    
+    // None if there is no such device (any more)
+    pub fn get_device(&self, device_id: xcb_input_device_id_t) -> Result<Option<XcbInputDevice>, XcbError> {
+        match self.query_devices(device_id) {
+            Ok(mut devices) => Ok(devices.devices.pop()),
+            Err(XcbError::ProtoError(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    
+    fn query_devices(&self, device_id: xcb_input_device_id_t) -> Result<XcbInputDevices, XcbError> {
+        let cookie = unsafe { xcb_input_xi_query_device(self.connection.value, device_id) };
         let reply = try!(get_reply(self.connection, cookie, xcb_input_xi_query_device_reply));
         
         let devices_it = XcbIterator::new(unsafe { xcb_input_xi_query_device_infos_iterator(reply.value) }, xcb_input_xi_device_info_next);
@@ -422,12 +780,27 @@ impl <'a> XcbInput<'a> {
                     )
                 }.to_vec()
             ).unwrap();
+            let classes_it: XcbIterator<_, xcb_input_device_class_t> = XcbIterator::new(unsafe { xcb_input_xi_device_info_classes_iterator(x) }, xcb_input_device_class_next);
+            let valuators: Vec<_> = classes_it
+                .filter(|c| c._type as c_uint == XCB_INPUT_DEVICE_CLASS_TYPE_VALUATOR)
+                .map(|c| {
+                    let v = unsafe { &*(c as *const _ as *const xcb_input_valuator_class_t) };
+                    XcbInputValuator {
+                        number: v.number,
+                        label: v.label,
+                        min: fp3232_to_f64(v.min),
+                        max: fp3232_to_f64(v.max),
+                        resolution: v.resolution,
+                    }
+                })
+                .collect();
             XcbInputDevice {
                 deviceid: x.deviceid,
                 _type: x._type,
                 attachment: x.attachment,
                 enabled: x.enabled != 0,
                 name: name,
+                valuators: valuators,
             }
         }).collect();
         
@@ -437,15 +810,7 @@ impl <'a> XcbInput<'a> {
     }
     
     pub fn get_device_properties(&self, device_id: xcb_input_device_id_t) -> Result<Vec<String>, XcbError> {
-        let cookie = unsafe { xcb_input_xi_list_properties(self.connection.value, device_id) };
-        let reply = try!(get_reply(self.connection, cookie, xcb_input_xi_list_properties_reply));
-        
-        let atoms = unsafe {
-            slice::from_raw_parts(
-                xcb_input_xi_list_properties_properties(reply.value),
-                xcb_input_xi_list_properties_properties_length(reply.value) as usize
-            )
-        }.to_vec();
+        let atoms = try!(self.get_device_property_atoms(device_id));
         
         let names_wrapped: Vec<_> = atoms
             .iter()
@@ -477,6 +842,100 @@ impl <'a> XcbInput<'a> {
         Ok(names)
     }
     
+    // Same as get_device_properties, without resolving the names of the atoms
+    pub fn get_device_property_atoms(&self, device_id: xcb_input_device_id_t) -> Result<Vec<xcb_atom_t>, XcbError> {
+        let cookie = unsafe { xcb_input_xi_list_properties(self.connection.value, device_id) };
+        let reply = try!(get_reply(self.connection, cookie, xcb_input_xi_list_properties_reply));
+        
+        let atoms = unsafe {
+            slice::from_raw_parts(
+                xcb_input_xi_list_properties_properties(reply.value),
+                xcb_input_xi_list_properties_properties_length(reply.value) as usize
+            )
+        }.to_vec();
+        Ok(atoms)
+    }
+    
+    // Devices whose state is changed by an XI2 HierarchyChanged event, None for other events
+    pub fn decode_hierarchy_event(&self, event: &xcb_generic_event_t) -> Option<Vec<XcbInputHierarchyChange>> {
+        if event.response_type & 0x7f != 35 /* XCB_GE_GENERIC */ {
+            return None;
+        }
+        let ge = unsafe { &*(event as *const _ as *const xcb_ge_generic_event_t) };
+        if ge.extension != self.extension.major_opcode || ge.event_type != 11 /* XCB_INPUT_HIERARCHY */ {
+            return None;
+        }
+        let hierarchy = unsafe { &*(event as *const _ as *const xcb_input_hierarchy_event_t) };
+        // The list of infos follows the event (as in xcb_input_hierarchy_infos)
+        let infos = unsafe {
+            slice::from_raw_parts(
+                (hierarchy as *const xcb_input_hierarchy_event_t).offset(1) as *const xcb_input_hierarchy_info_t,
+                hierarchy.num_infos as usize
+            )
+        };
+        Some(infos.iter().filter(|info| info.flags != 0).map(|info| XcbInputHierarchyChange {
+            deviceid: info.deviceid,
+            _type: info._type as uint16_t,
+            attachment: info.attachment,
+            enabled: info.enabled != 0,
+            flags: info.flags,
+        }).collect())
+    }
+    
+    pub fn get_device_product_id(&self, device_id: xcb_input_device_id_t) -> Result<Option<(u32, u32)>, XcbError> {
+        let property = try!(self.connection.intern_atom("Device Product ID", true));
+        if property == XCB_ATOM_NONE {
+            return Ok(None);
+        }
+        match self.get_property_value::<u32>(device_id, property, XCB_ATOM_INTEGER) {
+            Ok(ref items) if items.len() >= 2 => Ok(Some((items[0], items[1]))),
+            // Devices that are not backed by a kernel device have no product ID
+            Ok(_) | Err(XcbError::LogicError(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    
+    // Reads a whole property, checking that it has the type `proptype` and the format of PropT
+    pub fn get_property_value<PropT: XcbPropertyItem>(
+        &self, device_id: xcb_input_device_id_t,
+        property: xcb_atom_t, proptype: xcb_atom_t
+    ) -> Result<Vec<PropT>, XcbError>
+    {
+        let mut length = 64; // in 32-bit units
+        loop {
+            let cookie = unsafe { xcb_input_xi_get_property(self.connection.value, device_id, 0, property, proptype, 0, length) };
+            let reply = try!(get_reply(self.connection, cookie, xcb_input_xi_get_property_reply));
+            
+            if reply._type == XCB_ATOM_NONE {
+                return Err(XcbError::LogicError(format!(
+                    "Device {} has no property {}", device_id, try!(self.connection.get_atom_name(property))
+                )));
+            }
+            if reply._type != proptype {
+                return Err(XcbError::LogicError(format!(
+                    "Property {} of device {} has type {}, expected {}",
+                    try!(self.connection.get_atom_name(property)), device_id,
+                    try!(self.connection.get_atom_name(reply._type)), try!(self.connection.get_atom_name(proptype))
+                )));
+            }
+            if reply.format != PropT::format() {
+                return Err(XcbError::LogicError(format!(
+                    "Property {} of device {} has format {}, expected {}",
+                    try!(self.connection.get_atom_name(property)), device_id, reply.format, PropT::format()
+                )));
+            }
+            if reply.bytes_after > 0 {
+                length += (reply.bytes_after + 3) / 4;
+                continue;
+            }
+            
+            let items = unsafe {
+                slice::from_raw_parts(xcb_input_xi_get_property_items(reply.value) as *const PropT, reply.num_items as usize)
+            }.to_vec();
+            return Ok(items);
+        }
+    }
+    
     pub fn set_property_value<PropT>(
         &self, device_id: xcb_input_device_id_t,
         property: xcb_atom_t, proptype: xcb_atom_t,
@@ -500,6 +959,11 @@ impl <'a> XcbInput<'a> {
         Ok(())
     }
     
+    pub fn set_device_enabled(&self, device_id: xcb_input_device_id_t, enabled: bool) -> Result<(), XcbError> {
+        let property = try!(self.connection.intern_atom("Device Enabled", true));
+        self.set_property_value(device_id, property, XCB_ATOM_INTEGER, 8, &[enabled as uint8_t])
+    }
+    
     pub fn select_device_add_remove_events(&self, root_window_id: xcb_window_t) -> Result<(), XcbError> {
         let mask = XcbInputEventMask {
             xcb_data: xcb_input_event_mask_t {
@@ -516,6 +980,19 @@ impl <'a> XcbInput<'a> {
     }
 }
 
+// Types that items of an XI2 device property can be read as
+pub trait XcbPropertyItem: Copy {
+    fn format() -> u8;
+}
+
+impl XcbPropertyItem for u8 { fn format() -> u8 { 8 } }
+impl XcbPropertyItem for i8 { fn format() -> u8 { 8 } }
+impl XcbPropertyItem for u16 { fn format() -> u8 { 16 } }
+impl XcbPropertyItem for i16 { fn format() -> u8 { 16 } }
+impl XcbPropertyItem for u32 { fn format() -> u8 { 32 } }
+impl XcbPropertyItem for i32 { fn format() -> u8 { 32 } }
+impl XcbPropertyItem for f32 { fn format() -> u8 { 32 } }
+
 #[repr(C)]
 struct XcbInputEventMask {
     pub xcb_data: xcb_input_event_mask_t,
@@ -534,4 +1011,70 @@ pub struct XcbInputDevice {
     pub attachment: xcb_input_device_id_t,
     pub enabled: bool,
     pub name: String,
-}
\ No newline at end of file
+    pub valuators: Vec<XcbInputValuator>,
+}
+
+// Entry of an XI2 HierarchyChanged event; `flags` is a combination of XCB_INPUT_HIERARCHY_MASK_*
+#[derive(Debug,Clone)]
+pub struct XcbInputHierarchyChange {
+    pub deviceid: xcb_input_device_id_t,
+    pub _type: uint16_t,
+    pub attachment: xcb_input_device_id_t,
+    pub enabled: bool,
+    pub flags: uint32_t,
+}
+
+impl XcbInputHierarchyChange {
+    pub fn added(&self) -> bool {
+        self.flags & (XCB_INPUT_HIERARCHY_MASK_MASTER_ADDED | XCB_INPUT_HIERARCHY_MASK_SLAVE_ADDED) != 0
+    }
+    
+    pub fn removed(&self) -> bool {
+        self.flags & (XCB_INPUT_HIERARCHY_MASK_MASTER_REMOVED | XCB_INPUT_HIERARCHY_MASK_SLAVE_REMOVED) != 0
+    }
+    
+    pub fn device_enabled(&self) -> bool {
+        self.flags & XCB_INPUT_HIERARCHY_MASK_DEVICE_ENABLED != 0
+    }
+}
+
+impl XcbInputDevice {
+    pub fn get_valuator(&self, number: u16) -> Option<&XcbInputValuator> {
+        self.valuators.iter().filter(|v| v.number == number).nth(0)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct XcbInputValuator {
+    pub number: uint16_t,
+    pub label: xcb_atom_t,
+    pub min: f64,
+    pub max: f64,
+    pub resolution: uint32_t,
+}
+
+fn fp3232_to_f64(value: xcb_input_fp3232_t) -> f64 {
+    value.integral as f64 + value.frac as f64 / 4294967296.0
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum XcbInputDeviceType {
+    MasterPointer = 1,
+    MasterKeyboard = 2,
+    SlavePointer = 3,
+    SlaveKeyboard = 4,
+    FloatingSlave = 5,
+}
+
+impl XcbInputDeviceType {
+    pub fn from_u16(val: u16) -> Option<XcbInputDeviceType> {
+        match val {
+            1 => Some(XcbInputDeviceType::MasterPointer),
+            2 => Some(XcbInputDeviceType::MasterKeyboard),
+            3 => Some(XcbInputDeviceType::SlavePointer),
+            4 => Some(XcbInputDeviceType::SlaveKeyboard),
+            5 => Some(XcbInputDeviceType::FloatingSlave),
+            _ => None
+        }
+    }
+}